@@ -38,17 +38,8 @@ fn main () {
     let init = ioq::net::initialize();
     let queue = ioq::Queue::new().unwrap();
 
-    // Define remote address (use a google.com address)
-    let ip = ioq::net::IpAddr::V4(ioq::net::Ipv4Addr::new(216, 58, 193, 100));
-    let remote = ioq::net::SocketAddr::new(ip, 80);
-
-    // Define local address
-    let ip = ioq::net::IpAddr::V4(ioq::net::Ipv4Addr::new(0, 0, 0, 0));
-    let local = ioq::net::SocketAddr::new(ip, 0);
-
-    // Create stream and connect
-    let stream = ioq::net::TcpStream::new(local, queue.clone()).unwrap();
-    stream.connect(remote).unwrap();
+    // Resolve remote address
+    ioq::net::resolve("www.google.com", 80, queue.clone()).unwrap();
 
     // Get document
     let mut bytes: Vec<u8> = Vec::new();
@@ -56,6 +47,18 @@ fn main () {
     const BUFFER_BYTES: usize = 512 * 1024;
     loop {
         match queue.dequeue().unwrap() {
+            ioq::Event::Resolved(_, _, result) => match result {
+                Ok(addrs) => {
+                    println!("resolved: {:?}", addrs);
+
+                    // Create stream and connect
+                    ioq::net::TcpStream::connect_any(addrs, queue.clone()).unwrap();
+                },
+                Err(e) => {
+                    panic!("resolve error: {:?}", e);
+                },
+            },
+
             ioq::Event::TcpConnect(stream, result) => match result {
                 Ok(()) => {
                     println!("connect");
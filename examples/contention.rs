@@ -0,0 +1,157 @@
+/****************************************************************************
+*
+*   examples/contention.rs
+*   ioq
+*
+*   Copyright 2015 Tyler Cole
+*
+***/
+
+extern crate ioq;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+
+
+/****************************************************************************
+*
+*   Constants
+*
+***/
+
+const THREADS: usize = 8;
+const SENDS: usize = 200000;
+const SEND_BYTES: usize = 64;
+const SENDS_IN_FLIGHT: usize = 64;
+const RECEIVES_IN_FLIGHT: usize = 4;
+const RECEIVE_BYTES: usize = 64 * 1024;
+
+
+/****************************************************************************
+*
+*   Stop
+*
+***/
+
+struct Stop;
+
+impl ioq::Custom for Stop {
+    fn execute (self: Box<Self>) {}
+}
+
+
+/****************************************************************************
+*
+*   Private functions
+*
+***/
+
+//===========================================================================
+fn connected_pair (queue: &ioq::Queue) -> (ioq::net::TcpStream, ioq::net::TcpStream) {
+    let ip = ioq::net::IpAddr::V4(ioq::net::Ipv4Addr::new(127, 0, 0, 1));
+    let listener = ioq::net::TcpListener::new(ioq::net::SocketAddr::new(ip, 0), queue.clone()).unwrap();
+    listener.accept().unwrap();
+    ioq::net::TcpStream::connect_to(listener.addr(), queue.clone()).unwrap();
+
+    let mut server = None;
+    let mut client = None;
+    while server.is_none() || client.is_none() {
+        match queue.dequeue().unwrap() {
+            ioq::Event::TcpAccept(_, result) => server = Some(result.unwrap()),
+            ioq::Event::TcpConnect(stream, result) => {
+                result.unwrap();
+                client = Some(stream);
+            },
+            _ => panic!("Unexpected event"),
+        }
+    }
+
+    (server.unwrap(), client.unwrap())
+}
+
+//===========================================================================
+// Sends SENDS buffers over one stream with THREADS threads dequeuing and
+// resubmitting, reading the stream's addresses on each completion as a
+// server logging its peers would. Returns sends per second.
+fn run () -> f64 {
+    let queue = ioq::Queue::new().unwrap();
+    let (server, client) = connected_pair(&queue);
+
+    for _ in 0..RECEIVES_IN_FLIGHT {
+        server.receive(vec![0u8; RECEIVE_BYTES].into_boxed_slice()).unwrap();
+    }
+
+    let submitted = Arc::new(AtomicUsize::new(SENDS_IN_FLIGHT));
+    let completed = Arc::new(AtomicUsize::new(0));
+    for _ in 0..SENDS_IN_FLIGHT {
+        client.send(vec![0u8; SEND_BYTES].into_boxed_slice()).unwrap();
+    }
+
+    let start = Instant::now();
+    let workers: Vec<_> = (0..THREADS).map(|_| {
+        let queue = queue.clone();
+        let submitted = submitted.clone();
+        let completed = completed.clone();
+
+        thread::spawn(move || {
+            loop {
+                match queue.dequeue().unwrap() {
+                    ioq::Event::TcpSend(stream, buffer, result) => {
+                        result.unwrap();
+                        if completed.fetch_add(1, Ordering::SeqCst) + 1 == SENDS {
+                            for _ in 0..THREADS {
+                                queue.enqueue(Box::new(Stop)).unwrap();
+                            }
+                        }
+                        if submitted.fetch_add(1, Ordering::SeqCst) >= SENDS {
+                            continue;
+                        }
+
+                        stream.addr_remote();
+                        stream.send(buffer).unwrap();
+                    },
+                    ioq::Event::TcpReceive(stream, buffer, result) => {
+                        if result.unwrap() == 0 {
+                            continue;
+                        }
+
+                        stream.addr_local();
+                        stream.receive(buffer).unwrap();
+                    },
+                    ioq::Event::Custom => break,
+                    _ => panic!("Unexpected event"),
+                }
+            }
+        })
+    }).collect();
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    let elapsed = start.elapsed();
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    SENDS as f64 / seconds
+}
+
+
+/****************************************************************************
+*
+*   Main
+*
+***/
+
+//===========================================================================
+// Measures whatever TcpStream the crate is built with. To compare designs,
+// run it at the commit that made TcpStream lock-free and at its parent,
+// where each stream was an Arc<Mutex<TcpStreamInner>>, on the same machine.
+fn main () {
+    let init = ioq::net::initialize();
+
+    println!("{} threads: {:.0} sends/s", THREADS, run());
+
+    // Ensure init guard survives until the end
+    let _ = init;
+}
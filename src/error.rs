@@ -7,12 +7,14 @@
 *
 ***/
 
-use std::cell::RefCell;
 use std::error;
 use std::fmt;
-use std::mem;
+use std::io;
 use std::ptr;
+use std::slice;
+use std::time::{Duration, Instant};
 
+use net::SocketAddr;
 use sys;
 
 
@@ -22,9 +24,10 @@ use sys;
 *
 ***/
 
-#[derive(Debug)]
 pub struct Error {
-    inner: Inner
+    inner: Inner,
+    buffer: Option<Box<[u8]>>,
+    operation: Option<Box<OperationInfo>>,
 }
 
 impl Error {
@@ -36,7 +39,9 @@ impl Error {
             inner: Inner::Custom(Box::new(Custom {
                 kind: kind,
                 error: error.into(),
-            }))
+            })),
+            buffer: None,
+            operation: None,
         }
     }
 
@@ -50,6 +55,11 @@ impl Error {
         Error::new(ErrorKind::NotImplemented, "Functionality not implemented")
     }
 
+    //=======================================================================
+    pub fn timed_out () -> Error {
+        Error::new(ErrorKind::TimedOut, "Operation timed out")
+    }
+
     //=======================================================================
     pub fn os_error_code (&self) -> Option<i32> {
         match self.inner {
@@ -60,7 +70,7 @@ impl Error {
 
     //=======================================================================
     pub fn from_os_error_code (code: i32) -> Error {
-        Error { inner: Inner::Os(code) }
+        Error { inner: Inner::Os(code), buffer: None, operation: None }
     }
 
     //=======================================================================
@@ -69,22 +79,77 @@ impl Error {
     }
 
     //=======================================================================
-    pub fn kind (&self) -> Option<ErrorKind> {
+    pub fn kind (&self) -> ErrorKind {
         match self.inner {
-            Inner::Os(..) => None,
-            Inner::Custom(ref c) => Some(c.kind),
+            Inner::Os(code) => decode_error_kind(code),
+            Inner::Custom(ref c) => c.kind,
+        }
+    }
+
+    //=======================================================================
+    // Attaches the buffer of an operation that failed before it was
+    // submitted, so that the caller can reuse it
+    pub fn with_buffer (mut self, buffer: Option<Box<[u8]>>) -> Error {
+        self.buffer = buffer;
+        self
+    }
+
+    //=======================================================================
+    pub fn take_buffer (&mut self) -> Option<Box<[u8]>> {
+        self.buffer.take()
+    }
+
+    //=======================================================================
+    pub fn with_operation (mut self, operation: OperationInfo) -> Error {
+        self.operation = Some(Box::new(operation));
+        self
+    }
+
+    //=======================================================================
+    // Describes the operation that failed, if the error came from one
+    pub fn operation (&self) -> Option<&OperationInfo> {
+        match self.operation {
+            Some(ref operation) => Some(&**operation),
+            None => None,
+        }
+    }
+
+    //=======================================================================
+    // Returns any attached buffer alongside the io::Error, which has no
+    // room for it
+    pub fn into_io (mut self) -> (io::Error, Option<Box<[u8]>>) {
+        let buffer = self.take_buffer();
+        (self.into(), buffer)
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = fmt.debug_struct("Error");
+        debug.field("inner", &self.inner);
+        if let Some(ref operation) = self.operation {
+            debug.field("operation", operation);
         }
+        if let Some(ref buffer) = self.buffer {
+            debug.field("buffer_len", &buffer.len());
+        }
+        debug.finish()
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        match self.inner {
+        let result = match self.inner {
             Inner::Os(code) => {
                 let detail = error_string(code);
                 write!(fmt, "{} (os error {})", detail, code)
             }
             Inner::Custom(ref c) => c.error.fmt(fmt),
+        };
+
+        match self.operation {
+            Some(ref operation) if result.is_ok() => write!(fmt, " {}", operation),
+            _ => result,
         }
     }
 }
@@ -98,6 +163,130 @@ impl error::Error for Error {
     }
 }
 
+impl From<Error> for io::Error {
+    //=======================================================================
+    // Only a bare OS error becomes a raw OS error. Anything else, including
+    // an OS error with an operation or buffer attached, is wrapped whole so
+    // that converting back restores it; use into_io to take the buffer out
+    // first.
+    fn from (error: Error) -> io::Error {
+        if error.operation.is_none() && error.buffer.is_none() {
+            if let Inner::Os(code) = error.inner {
+                return io::Error::from_raw_os_error(code);
+            }
+        }
+
+        let kind = into_io_kind(error.kind());
+        io::Error::new(kind, error)
+    }
+}
+
+impl From<io::Error> for Error {
+    //=======================================================================
+    fn from (error: io::Error) -> Error {
+        if let Some(code) = error.raw_os_error() {
+            return Error::from_os_error_code(code);
+        }
+
+        let kind = from_io_kind(error.kind());
+        let message = error.to_string();
+        match error.into_inner() {
+            Some(inner) => {
+                match inner.downcast::<Error>() {
+                    Ok(error) => *error,
+                    Err(inner) => Error::new(kind, inner),
+                }
+            },
+            None => Error::new(kind, message),
+        }
+    }
+}
+
+
+/****************************************************************************
+*
+*   Operation
+*
+***/
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Operation {
+    Accept,
+    Connect,
+    Send,
+    Receive,
+}
+
+impl fmt::Display for Operation {
+    //=======================================================================
+    fn fmt (&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Operation::Accept => "accept",
+            Operation::Connect => "connect",
+            Operation::Send => "send",
+            Operation::Receive => "receive",
+        };
+        fmt.write_str(name)
+    }
+}
+
+
+/****************************************************************************
+*
+*   OperationInfo
+*
+***/
+
+#[derive(Clone, Debug)]
+pub struct OperationInfo {
+    pub operation: Operation,
+    pub local: Option<SocketAddr>,
+    pub remote: Option<SocketAddr>,
+    pub submitted: Instant,
+    pub elapsed: Duration, // From submission until the failure was reported
+}
+
+impl OperationInfo {
+    //=======================================================================
+    // Call once the operation has failed
+    pub fn new (
+        operation: Operation,
+        local: Option<SocketAddr>,
+        remote: Option<SocketAddr>,
+        submitted: Instant
+    ) -> OperationInfo {
+        OperationInfo {
+            operation: operation,
+            local: local,
+            remote: remote,
+            submitted: submitted,
+            elapsed: submitted.elapsed(),
+        }
+    }
+}
+
+impl fmt::Display for OperationInfo {
+    //=======================================================================
+    fn fmt (&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let millis = self.elapsed.as_secs() * 1000 + (self.elapsed.subsec_nanos() / 1_000_000) as u64;
+
+        if let Err(error) = write!(fmt, "during {} (", self.operation) {
+            return Err(error);
+        }
+        if let Some(local) = self.local {
+            if let Err(error) = write!(fmt, "local {}, ", local) {
+                return Err(error);
+            }
+        }
+        if let Some(remote) = self.remote {
+            if let Err(error) = write!(fmt, "remote {}, ", remote) {
+                return Err(error);
+            }
+        }
+        write!(fmt, "{} ms after submit)", millis)
+    }
+}
+
 
 /****************************************************************************
 *
@@ -145,6 +334,25 @@ struct Custom {
 pub enum ErrorKind {
     Unknown,
     NotImplemented,
+    InvalidInput,
+    TimedOut,
+    InvalidData,
+    NotFound,
+    AlreadyExists,
+    PermissionDenied,
+    ConnectionRefused,
+    ConnectionReset,
+    ConnectionAborted,
+    NotConnected,
+    AddrInUse,
+    AddrNotAvailable,
+    HostUnreachable,
+    NetworkUnreachable,
+    BrokenPipe,
+    WouldBlock,
+    Interrupted,
+    Cancelled,
+    Other, // An OS error with no more specific kind
 }
 
 
@@ -154,8 +362,116 @@ pub enum ErrorKind {
 *
 ***/
 
-const MESSAGE_BYTES: usize = 128;
-thread_local!(static MESSAGE: RefCell<[u8; MESSAGE_BYTES]> = RefCell::new([0; MESSAGE_BYTES]));
+//=======================================================================
+// Covers both system and Winsock codes, which share one number space
+pub fn decode_error_kind (code: i32) -> ErrorKind {
+    match code {
+        sys::ERROR_FILE_NOT_FOUND
+        | sys::ERROR_PATH_NOT_FOUND
+        | sys::ERROR_NOT_FOUND
+        | sys::WSAHOST_NOT_FOUND
+        | sys::WSANO_DATA => ErrorKind::NotFound,
+
+        sys::ERROR_FILE_EXISTS
+        | sys::ERROR_ALREADY_EXISTS => ErrorKind::AlreadyExists,
+
+        sys::ERROR_ACCESS_DENIED
+        | sys::WSAEACCES => ErrorKind::PermissionDenied,
+
+        sys::ERROR_INVALID_HANDLE
+        | sys::ERROR_INVALID_PARAMETER
+        | sys::WSAEINVAL => ErrorKind::InvalidInput,
+
+        sys::ERROR_NOT_SUPPORTED
+        | sys::ERROR_CALL_NOT_IMPLEMENTED => ErrorKind::NotImplemented,
+
+        sys::ERROR_CONNECTION_REFUSED
+        | sys::ERROR_PORT_UNREACHABLE
+        | sys::WSAECONNREFUSED => ErrorKind::ConnectionRefused,
+
+        // A reset connection fails overlapped operations with
+        // ERROR_NETNAME_DELETED rather than the Winsock code
+        sys::ERROR_NETNAME_DELETED
+        | sys::WSAENETRESET
+        | sys::WSAECONNRESET => ErrorKind::ConnectionReset,
+
+        sys::ERROR_CONNECTION_ABORTED
+        | sys::WSAECONNABORTED => ErrorKind::ConnectionAborted,
+
+        sys::WSAENOTCONN => ErrorKind::NotConnected,
+        sys::WSAEADDRINUSE => ErrorKind::AddrInUse,
+        sys::WSAEADDRNOTAVAIL => ErrorKind::AddrNotAvailable,
+
+        sys::ERROR_HOST_UNREACHABLE
+        | sys::WSAEHOSTUNREACH => ErrorKind::HostUnreachable,
+
+        sys::ERROR_NETWORK_UNREACHABLE
+        | sys::WSAENETUNREACH => ErrorKind::NetworkUnreachable,
+
+        sys::ERROR_BROKEN_PIPE
+        | sys::ERROR_NO_DATA
+        | sys::WSAESHUTDOWN => ErrorKind::BrokenPipe,
+
+        sys::ERROR_SEM_TIMEOUT
+        | sys::WAIT_TIMEOUT
+        | sys::ERROR_TIMEOUT
+        | sys::WSAETIMEDOUT => ErrorKind::TimedOut,
+
+        sys::WSAEWOULDBLOCK => ErrorKind::WouldBlock,
+
+        sys::WSAEINTR => ErrorKind::Interrupted,
+
+        sys::ERROR_OPERATION_ABORTED
+        | sys::ERROR_CANCELLED
+        | sys::WSAECANCELLED => ErrorKind::Cancelled,
+
+        _ => ErrorKind::Other,
+    }
+}
+
+//=======================================================================
+fn into_io_kind (kind: ErrorKind) -> io::ErrorKind {
+    match kind {
+        ErrorKind::InvalidInput => io::ErrorKind::InvalidInput,
+        ErrorKind::TimedOut => io::ErrorKind::TimedOut,
+        ErrorKind::InvalidData => io::ErrorKind::InvalidData,
+        ErrorKind::NotFound => io::ErrorKind::NotFound,
+        ErrorKind::AlreadyExists => io::ErrorKind::AlreadyExists,
+        ErrorKind::PermissionDenied => io::ErrorKind::PermissionDenied,
+        ErrorKind::ConnectionRefused => io::ErrorKind::ConnectionRefused,
+        ErrorKind::ConnectionReset => io::ErrorKind::ConnectionReset,
+        ErrorKind::ConnectionAborted => io::ErrorKind::ConnectionAborted,
+        ErrorKind::NotConnected => io::ErrorKind::NotConnected,
+        ErrorKind::AddrInUse => io::ErrorKind::AddrInUse,
+        ErrorKind::AddrNotAvailable => io::ErrorKind::AddrNotAvailable,
+        ErrorKind::BrokenPipe => io::ErrorKind::BrokenPipe,
+        ErrorKind::WouldBlock => io::ErrorKind::WouldBlock,
+        ErrorKind::Interrupted => io::ErrorKind::Interrupted,
+        _ => io::ErrorKind::Other,
+    }
+}
+
+//=======================================================================
+fn from_io_kind (kind: io::ErrorKind) -> ErrorKind {
+    match kind {
+        io::ErrorKind::InvalidInput => ErrorKind::InvalidInput,
+        io::ErrorKind::TimedOut => ErrorKind::TimedOut,
+        io::ErrorKind::InvalidData => ErrorKind::InvalidData,
+        io::ErrorKind::NotFound => ErrorKind::NotFound,
+        io::ErrorKind::AlreadyExists => ErrorKind::AlreadyExists,
+        io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+        io::ErrorKind::ConnectionRefused => ErrorKind::ConnectionRefused,
+        io::ErrorKind::ConnectionReset => ErrorKind::ConnectionReset,
+        io::ErrorKind::ConnectionAborted => ErrorKind::ConnectionAborted,
+        io::ErrorKind::NotConnected => ErrorKind::NotConnected,
+        io::ErrorKind::AddrInUse => ErrorKind::AddrInUse,
+        io::ErrorKind::AddrNotAvailable => ErrorKind::AddrNotAvailable,
+        io::ErrorKind::BrokenPipe => ErrorKind::BrokenPipe,
+        io::ErrorKind::WouldBlock => ErrorKind::WouldBlock,
+        io::ErrorKind::Interrupted => ErrorKind::Interrupted,
+        _ => ErrorKind::Other,
+    }
+}
 
 //=======================================================================
 pub fn last_error_code () -> i32 {
@@ -163,32 +479,182 @@ pub fn last_error_code () -> i32 {
 }
 
 //=======================================================================
-pub fn error_string<'a> (code: i32) -> &'a str {
-    let mut message: &str = "";
-
-    MESSAGE.with(|m| {
-        let mut buffer: &mut [u8] = &mut *m.borrow_mut();
-
-        let count = unsafe {
-            sys::FormatMessageA(
-                  sys::FORMAT_MESSAGE_FROM_SYSTEM
-                | sys::FORMAT_MESSAGE_IGNORE_INSERTS
-                | sys::FORMAT_MESSAGE_MAX_WIDTH_MASK,
-                ptr::null(),
-                code as sys::DWORD,
-                0,
-                buffer.as_mut_ptr(),
-                buffer.len() as sys::DWORD,
-                ptr::null()
-            )
-        };
+// Returns the system message for code, allocated by FormatMessageW to fit
+pub fn error_string (code: i32) -> String {
+    let mut buffer: sys::LPWSTR = ptr::null_mut();
+
+    let count = unsafe {
+        sys::FormatMessageW(
+              sys::FORMAT_MESSAGE_ALLOCATE_BUFFER
+            | sys::FORMAT_MESSAGE_FROM_SYSTEM
+            | sys::FORMAT_MESSAGE_IGNORE_INSERTS
+            | sys::FORMAT_MESSAGE_MAX_WIDTH_MASK,
+            ptr::null(),
+            code as sys::DWORD,
+            0,
+            &mut buffer as *mut sys::LPWSTR as sys::LPWSTR,
+            0,
+            ptr::null()
+        )
+    };
+
+    if count == 0 {
+        return format!("Unknown error {}", code);
+    }
+
+    let message = unsafe {
+        let message = slice::from_raw_parts(buffer, count as usize);
+
+        // Line breaks are already replaced with spaces, leaving a trailing one
+        let is_space = |c: u16| c == b' ' as u16 || c == b'\r' as u16 || c == b'\n' as u16;
+        let mut len = message.len();
+        while len > 0 && is_space(message[len - 1]) {
+            len -= 1;
+        }
+
+        String::from_utf16_lossy(&message[..len])
+    };
+
+    unsafe { sys::LocalFree(buffer as sys::HLOCAL) };
+    message
+}
+
 
-        message = unsafe { mem::transmute(&buffer[..count as usize]) };
-    });
+/****************************************************************************
+*
+*   Tests
+*
+***/
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Instant;
 
-    if message.len() == 0 && last_error_code() == sys::ERROR_INSUFFICIENT_BUFFER {
-        message = "[MESSAGE buffer not large enough]";
+    use net::SocketAddr;
+    use sys;
+    use super::*;
+
+    //=======================================================================
+    #[test]
+    fn os_error_kind () {
+        let kinds = [
+            (sys::WSAECONNREFUSED, ErrorKind::ConnectionRefused),
+            (sys::WSAECONNRESET, ErrorKind::ConnectionReset),
+            (sys::ERROR_NETNAME_DELETED, ErrorKind::ConnectionReset),
+            (sys::WSAETIMEDOUT, ErrorKind::TimedOut),
+            (sys::WSAEADDRINUSE, ErrorKind::AddrInUse),
+            (sys::ERROR_OPERATION_ABORTED, ErrorKind::Cancelled),
+            (sys::ERROR_ACCESS_DENIED, ErrorKind::PermissionDenied),
+            (1, ErrorKind::Other),
+        ];
+
+        for &(code, kind) in kinds.iter() {
+            assert_eq!(Error::from_os_error_code(code).kind(), kind);
+        }
+        assert_eq!(Error::timed_out().kind(), ErrorKind::TimedOut);
     }
 
-    return message;
-}
\ No newline at end of file
+    //=======================================================================
+    #[test]
+    fn io_round_trip () {
+        let (error, buffer) = Error::from_os_error_code(sys::WSAECONNRESET)
+            .with_buffer(Some(Box::new([1u8, 2])))
+            .into_io();
+        assert_eq!(error.raw_os_error(), Some(sys::WSAECONNRESET));
+        assert_eq!(&buffer.unwrap()[..], &[1, 2]);
+        let error = Error::from(error);
+        assert_eq!(error.os_error_code(), Some(sys::WSAECONNRESET));
+
+        // Kinds without an io equivalent survive the round trip
+        let (error, buffer) = Error::new(ErrorKind::Cancelled, "cancelled").into_io();
+        assert!(buffer.is_none());
+        assert_eq!(error.kind(), io::ErrorKind::Other);
+        assert_eq!(Error::from(error).kind(), ErrorKind::Cancelled);
+
+        let error = Error::from(io::Error::new(io::ErrorKind::AddrInUse, "in use"));
+        assert_eq!(error.kind(), ErrorKind::AddrInUse);
+        assert_eq!(error.to_string(), "in use");
+
+        let error = Error::from(io::Error::from(io::ErrorKind::BrokenPipe));
+        assert_eq!(error.kind(), ErrorKind::BrokenPipe);
+    }
+
+    //=======================================================================
+    #[test]
+    fn operation_display () {
+        let local: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let remote: SocketAddr = "[::1]:80".parse().unwrap();
+        let info = OperationInfo::new(Operation::Receive, Some(local), Some(remote), Instant::now());
+
+        let error = Error::new(ErrorKind::ConnectionReset, "reset").with_operation(info);
+        assert_eq!(error.operation().unwrap().operation, Operation::Receive);
+        assert_eq!(
+            error.to_string(),
+            "reset during receive (local 127.0.0.1:5000, remote [::1]:80, 0 ms after submit)"
+        );
+
+        let info = OperationInfo::new(Operation::Accept, Some(local), None, Instant::now());
+        let error = Error::new(ErrorKind::Cancelled, "cancelled").with_operation(info);
+        assert_eq!(
+            error.to_string(),
+            "cancelled during accept (local 127.0.0.1:5000, 0 ms after submit)"
+        );
+        assert!(Error::timed_out().operation().is_none());
+    }
+
+    //=======================================================================
+    #[test]
+    fn operation_io_round_trip () {
+        let remote: SocketAddr = "127.0.0.1:80".parse().unwrap();
+        let info = OperationInfo::new(Operation::Connect, None, Some(remote), Instant::now());
+
+        let error = Error::from_os_error_code(sys::WSAECONNREFUSED).with_operation(info);
+        let (error, buffer) = error.into_io();
+        assert!(buffer.is_none());
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+
+        let error = Error::from(error);
+        assert_eq!(error.os_error_code(), Some(sys::WSAECONNREFUSED));
+        assert_eq!(error.operation().unwrap().remote, Some(remote));
+
+        // A buffer left attached is carried along too
+        let error = Error::new(ErrorKind::Cancelled, "cancelled")
+            .with_buffer(Some(Box::new([1u8, 2])));
+        let mut error = Error::from(io::Error::from(error));
+        assert_eq!(&error.take_buffer().unwrap()[..], &[1, 2]);
+    }
+
+    //=======================================================================
+    #[test]
+    fn long_message () {
+        // Longer than the fixed buffer messages used to be formatted into
+        let message = error_string(sys::WSAETIMEDOUT);
+        assert!(message.len() > 128, "{}", message);
+        assert!(!message.ends_with(' '));
+
+        let error = Error::from_os_error_code(sys::WSAETIMEDOUT);
+        assert_eq!(error.to_string(), format!("{} (os error {})", message, sys::WSAETIMEDOUT));
+        assert!(format!("{:?}", error).contains(&message));
+
+        // Each call returns its own string
+        let other = error_string(sys::WSAECONNRESET);
+        assert!(message != other);
+        assert_eq!(message, error_string(sys::WSAETIMEDOUT));
+    }
+
+    //=======================================================================
+    #[test]
+    fn unknown_message () {
+        assert_eq!(error_string(-1), "Unknown error -1");
+    }
+
+    //=======================================================================
+    #[test]
+    fn buffer () {
+        let buffer: Box<[u8]> = Box::new([1, 2, 3]);
+        let mut error = Error::from_os_error_code(sys::WSAENOTCONN).with_buffer(Some(buffer));
+        assert_eq!(&error.take_buffer().unwrap()[..], &[1, 2, 3]);
+        assert!(error.take_buffer().is_none());
+    }
+}
@@ -10,6 +10,8 @@
 use std::cmp;
 use std::fmt;
 use std::hash;
+use std::marker::PhantomData;
+use std::mem;
 
 use error::Error;
 use sys;
@@ -17,77 +19,253 @@ use sys;
 
 /****************************************************************************
 *
-*   Handle
+*   HandleRef
 *
 ***/
 
+// Handle borrowed from its owner for 'a. Never closes what it refers to,
+// so copies are harmless.
 #[derive(Copy)]
-pub struct Handle {
+pub struct HandleRef<'a> {
     raw: sys::HANDLE,
+    owner: PhantomData<&'a ()>,
 }
 
-impl Handle {
-    pub fn from_raw (raw: sys::HANDLE) -> Handle { Handle { raw: raw } }
+impl<'a> HandleRef<'a> {
     pub fn to_raw (&self) -> sys::HANDLE { self.raw }
+    pub fn to_socket (&self) -> sys::SOCKET { self.raw as sys::SOCKET }
     fn to_usize (&self) -> usize { self.raw as usize }
 
     //=======================================================================
-    pub fn close (self) -> Result<(), Error> {
-        let success = unsafe { sys::CloseHandle(self.raw) } != 0;
-
-        if success {
-            Ok(())
-        }
-        else {
-            Err(Error::os_error())
+    fn new (raw: sys::HANDLE) -> HandleRef<'a> {
+        HandleRef {
+            raw: raw,
+            owner: PhantomData,
         }
     }
 }
 
-impl Clone for Handle {
-    fn clone(&self) -> Handle { *self }
+// Kernel handles may be used from any thread
+unsafe impl<'a> Send for HandleRef<'a> {}
+unsafe impl<'a> Sync for HandleRef<'a> {}
+
+impl<'a> Clone for HandleRef<'a> {
+    fn clone(&self) -> HandleRef<'a> { *self }
 }
 
-impl fmt::Display for Handle {
+impl<'a> fmt::Display for HandleRef<'a> {
     //=======================================================================
     fn fmt (&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         write!(fmt, "{}", self.to_usize())
     }
 }
 
-impl fmt::Debug for Handle {
+impl<'a> fmt::Debug for HandleRef<'a> {
     //=======================================================================
     fn fmt (&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt::Display::fmt(self, fmt)
     }
 }
 
-impl PartialEq for Handle {
+impl<'a> PartialEq for HandleRef<'a> {
     //=======================================================================
-    fn eq (&self, other: &Handle) -> bool {
+    fn eq (&self, other: &HandleRef<'a>) -> bool {
         return self.raw == other.raw;
     }
 }
 
-impl Eq for Handle {}
+impl<'a> Eq for HandleRef<'a> {}
 
-impl hash::Hash for Handle {
+impl<'a> hash::Hash for HandleRef<'a> {
     //=======================================================================
     fn hash<H: hash::Hasher> (&self, s: &mut H) {
         self.to_usize().hash(s)
     }
 }
 
-impl PartialOrd for Handle {
+impl<'a> PartialOrd for HandleRef<'a> {
     //=======================================================================
-    fn partial_cmp (&self, other: &Handle) -> Option<cmp::Ordering> {
+    fn partial_cmp (&self, other: &HandleRef<'a>) -> Option<cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl Ord for Handle {
+impl<'a> Ord for HandleRef<'a> {
     //=======================================================================
-    fn cmp (&self, other: &Handle) -> cmp::Ordering {
+    fn cmp (&self, other: &HandleRef<'a>) -> cmp::Ordering {
         self.to_usize().cmp(&other.to_usize())
     }
-}
\ No newline at end of file
+}
+
+
+/****************************************************************************
+*
+*   OwnedHandle
+*
+***/
+
+// Kernel handle closed with CloseHandle exactly once, when dropped or closed
+pub struct OwnedHandle {
+    raw: sys::HANDLE,
+}
+
+impl OwnedHandle {
+    pub fn handle (&self) -> HandleRef<'_> { HandleRef::new(self.raw) }
+    pub fn to_raw (&self) -> sys::HANDLE { self.raw }
+
+    //=======================================================================
+    // Takes ownership of raw, which must be an open handle that nothing
+    // else will close
+    pub unsafe fn from_raw (raw: sys::HANDLE) -> OwnedHandle {
+        OwnedHandle { raw: raw }
+    }
+
+    //=======================================================================
+    // Releases ownership without closing the handle
+    pub fn into_raw (self) -> sys::HANDLE {
+        let raw = self.raw;
+        mem::forget(self);
+        raw
+    }
+
+    //=======================================================================
+    // Closes the handle, reporting any failure that dropping would ignore
+    pub fn close (self) -> Result<(), Error> {
+        let success = unsafe { sys::CloseHandle(self.into_raw()) } != 0;
+
+        if success {
+            Ok(())
+        }
+        else {
+            Err(Error::os_error())
+        }
+    }
+}
+
+unsafe impl Send for OwnedHandle {}
+unsafe impl Sync for OwnedHandle {}
+
+impl fmt::Debug for OwnedHandle {
+    //=======================================================================
+    fn fmt (&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_tuple("OwnedHandle").field(&self.handle()).finish()
+    }
+}
+
+impl Drop for OwnedHandle {
+    //=======================================================================
+    fn drop (&mut self) {
+        unsafe { sys::CloseHandle(self.raw) };
+    }
+}
+
+
+/****************************************************************************
+*
+*   OwnedSocket
+*
+***/
+
+// Socket closed with closesocket exactly once, when dropped or closed
+pub struct OwnedSocket {
+    raw: sys::SOCKET,
+}
+
+impl OwnedSocket {
+    pub fn handle (&self) -> HandleRef<'_> { HandleRef::new(self.raw as sys::HANDLE) }
+    pub fn to_raw (&self) -> sys::SOCKET { self.raw }
+
+    //=======================================================================
+    // Takes ownership of raw, which must be an open socket that nothing
+    // else will close
+    pub unsafe fn from_raw (raw: sys::SOCKET) -> OwnedSocket {
+        OwnedSocket { raw: raw }
+    }
+
+    //=======================================================================
+    // Releases ownership without closing the socket
+    pub fn into_raw (self) -> sys::SOCKET {
+        let raw = self.raw;
+        mem::forget(self);
+        raw
+    }
+
+    //=======================================================================
+    // Closes the socket, reporting any failure that dropping would ignore
+    pub fn close (self) -> Result<(), Error> {
+        let success = unsafe { sys::closesocket(self.into_raw()) } == 0;
+
+        if success {
+            Ok(())
+        }
+        else {
+            Err(Error::from_os_error_code(unsafe { sys::WSAGetLastError() }))
+        }
+    }
+}
+
+impl fmt::Debug for OwnedSocket {
+    //=======================================================================
+    fn fmt (&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_tuple("OwnedSocket").field(&self.handle()).finish()
+    }
+}
+
+impl Drop for OwnedSocket {
+    //=======================================================================
+    fn drop (&mut self) {
+        unsafe { sys::closesocket(self.raw) };
+    }
+}
+
+
+/****************************************************************************
+*
+*   Tests
+*
+***/
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+    use std::net as std_net;
+    use std::os::windows::io::IntoRawSocket;
+    use std::time::Duration;
+
+    use sys;
+    use super::*;
+
+    //=======================================================================
+    // Returns an owned client socket and its connected peer
+    fn connected () -> (OwnedSocket, std_net::TcpStream) {
+        let listener = std_net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std_net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let client = unsafe { OwnedSocket::from_raw(client.into_raw_socket() as sys::SOCKET) };
+        (client, server)
+    }
+
+    //=======================================================================
+    #[test]
+    fn socket_into_raw_leaves_open () {
+        let (client, mut server) = connected();
+        let raw = client.into_raw();
+
+        // The peer would see end of stream if the socket had been closed
+        server.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let error = server.read(&mut [0u8; 1]).err().unwrap();
+        assert!(error.kind() == io::ErrorKind::TimedOut || error.kind() == io::ErrorKind::WouldBlock);
+
+        unsafe { OwnedSocket::from_raw(raw) }.close().unwrap();
+        assert_eq!(server.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    //=======================================================================
+    #[test]
+    fn socket_drop_closes () {
+        let (client, mut server) = connected();
+        drop(client);
+        assert_eq!(server.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+}
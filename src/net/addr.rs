@@ -7,11 +7,19 @@
 *
 ***/
 
+use std::ffi::CString;
 use std::fmt;
+use std::io;
+use std::iter;
+use std::net;
 use std::option::IntoIter;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::slice;
+use std::str::FromStr;
+use std::vec;
 
-use error::Error;
+use sys;
+use error::{Error, ErrorKind};
 
 
 /****************************************************************************
@@ -32,6 +40,7 @@ pub trait ToSocketAddrs {
 *
 ***/
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum AddrFamily {
     V4,
     V6,
@@ -66,6 +75,86 @@ impl IpAddr {
             IpAddr::V6(..) => AddrFamily::V6,
         }
     }
+
+    //=======================================================================
+    pub fn is_unspecified (&self) -> bool {
+        match *self {
+            IpAddr::V4(ref a) => a.is_unspecified(),
+            IpAddr::V6(ref a) => a.is_unspecified(),
+        }
+    }
+
+    //=======================================================================
+    pub fn is_loopback (&self) -> bool {
+        match *self {
+            IpAddr::V4(ref a) => a.is_loopback(),
+            IpAddr::V6(ref a) => a.is_loopback(),
+        }
+    }
+
+    //=======================================================================
+    pub fn is_multicast (&self) -> bool {
+        match *self {
+            IpAddr::V4(ref a) => a.is_multicast(),
+            IpAddr::V6(ref a) => a.is_multicast(),
+        }
+    }
+
+    //=======================================================================
+    // True unless the address falls in one of the IANA special-purpose
+    // ranges that are not globally reachable
+    pub fn is_global (&self) -> bool {
+        match *self {
+            IpAddr::V4(ref a) => is_global_v4(a),
+            IpAddr::V6(ref a) => is_global_v6(a),
+        }
+    }
+}
+
+impl From<Ipv4Addr> for IpAddr {
+    //=======================================================================
+    fn from (ip: Ipv4Addr) -> IpAddr {
+        IpAddr::V4(ip)
+    }
+}
+
+impl From<Ipv6Addr> for IpAddr {
+    //=======================================================================
+    fn from (ip: Ipv6Addr) -> IpAddr {
+        IpAddr::V6(ip)
+    }
+}
+
+impl From<net::IpAddr> for IpAddr {
+    //=======================================================================
+    fn from (ip: net::IpAddr) -> IpAddr {
+        match ip {
+            net::IpAddr::V4(a) => IpAddr::V4(a),
+            net::IpAddr::V6(a) => IpAddr::V6(a),
+        }
+    }
+}
+
+impl From<IpAddr> for net::IpAddr {
+    //=======================================================================
+    fn from (ip: IpAddr) -> net::IpAddr {
+        match ip {
+            IpAddr::V4(a) => net::IpAddr::V4(a),
+            IpAddr::V6(a) => net::IpAddr::V6(a),
+        }
+    }
+}
+
+impl FromStr for IpAddr {
+    type Err = Error;
+
+    //=======================================================================
+    fn from_str (s: &str) -> Result<IpAddr, Error> {
+        match net::IpAddr::from_str(s) {
+            Ok(ip) => Ok(IpAddr::from(ip)),
+            Err(..) => Err(invalid_input()),
+        }
+    }
 }
 
 impl fmt::Display for IpAddr {
@@ -143,6 +232,65 @@ impl SocketAddr {
             SocketAddr::V6(..) => AddrFamily::V6,
         }
     }
+
+    //=======================================================================
+    // Converts a v4-mapped IPv6 address, as reported for IPv4 peers of a
+    // dual-stack socket, to the IPv4 address it maps
+    pub fn to_canonical (&self) -> SocketAddr {
+        match *self {
+            SocketAddr::V6(ref a) => match ipv4_mapped(a.ip()) {
+                Some(ip) => SocketAddr::V4(SocketAddrV4::new(ip, a.port())),
+                None => *self,
+            },
+            SocketAddr::V4(..) => *self,
+        }
+    }
+}
+
+impl From<SocketAddrV4> for SocketAddr {
+    //=======================================================================
+    fn from (addr: SocketAddrV4) -> SocketAddr {
+        SocketAddr::V4(addr)
+    }
+}
+
+impl From<SocketAddrV6> for SocketAddr {
+    //=======================================================================
+    fn from (addr: SocketAddrV6) -> SocketAddr {
+        SocketAddr::V6(addr)
+    }
+}
+
+impl From<net::SocketAddr> for SocketAddr {
+    //=======================================================================
+    fn from (addr: net::SocketAddr) -> SocketAddr {
+        match addr {
+            net::SocketAddr::V4(a) => SocketAddr::V4(a),
+            net::SocketAddr::V6(a) => SocketAddr::V6(a),
+        }
+    }
+}
+
+impl From<SocketAddr> for net::SocketAddr {
+    //=======================================================================
+    fn from (addr: SocketAddr) -> net::SocketAddr {
+        match addr {
+            SocketAddr::V4(a) => net::SocketAddr::V4(a),
+            SocketAddr::V6(a) => net::SocketAddr::V6(a),
+        }
+    }
+}
+
+impl FromStr for SocketAddr {
+    type Err = Error;
+
+    //=======================================================================
+    fn from_str (s: &str) -> Result<SocketAddr, Error> {
+        match parse_socket_addr(s) {
+            Some(addr) => Ok(addr),
+            None => Err(invalid_input()),
+        }
+    }
 }
 
 impl fmt::Display for SocketAddr {
@@ -162,4 +310,524 @@ impl ToSocketAddrs for SocketAddr {
     fn to_socket_addrs (&self) -> Result<IntoIter<SocketAddr>, Error> {
         Ok(Some(*self).into_iter())
     }
-}
\ No newline at end of file
+}
+
+impl ToSocketAddrs for net::SocketAddr {
+    type Iter = IntoIter<SocketAddr>;
+
+    //=======================================================================
+    fn to_socket_addrs (&self) -> Result<IntoIter<SocketAddr>, Error> {
+        Ok(Some(SocketAddr::from(*self)).into_iter())
+    }
+}
+
+impl ToSocketAddrs for (IpAddr, u16) {
+    type Iter = IntoIter<SocketAddr>;
+
+    //=======================================================================
+    fn to_socket_addrs (&self) -> Result<IntoIter<SocketAddr>, Error> {
+        let (ip, port) = *self;
+        Ok(Some(SocketAddr::new(ip, port)).into_iter())
+    }
+}
+
+impl<'a> ToSocketAddrs for (&'a str, u16) {
+    type Iter = vec::IntoIter<SocketAddr>;
+
+    //=======================================================================
+    fn to_socket_addrs (&self) -> Result<vec::IntoIter<SocketAddr>, Error> {
+        let (host, port) = *self;
+
+        // Try an IP literal before falling back to the system resolver
+        if let Some(addr) = parse_host(host, port) {
+            return Ok(vec![addr].into_iter());
+        }
+
+        resolve(net::ToSocketAddrs::to_socket_addrs(&(host, port)))
+    }
+}
+
+impl ToSocketAddrs for str {
+    type Iter = vec::IntoIter<SocketAddr>;
+
+    //=======================================================================
+    fn to_socket_addrs (&self) -> Result<vec::IntoIter<SocketAddr>, Error> {
+        // Try an address literal before falling back to the system resolver
+        if let Some(addr) = parse_socket_addr(self) {
+            return Ok(vec![addr].into_iter());
+        }
+
+        resolve(net::ToSocketAddrs::to_socket_addrs(self))
+    }
+}
+
+impl ToSocketAddrs for String {
+    type Iter = vec::IntoIter<SocketAddr>;
+
+    //=======================================================================
+    fn to_socket_addrs (&self) -> Result<vec::IntoIter<SocketAddr>, Error> {
+        (&self[..]).to_socket_addrs()
+    }
+}
+
+impl<'a> ToSocketAddrs for &'a [SocketAddr] {
+    type Iter = iter::Cloned<slice::Iter<'a, SocketAddr>>;
+
+    //=======================================================================
+    fn to_socket_addrs (&self) -> Result<Self::Iter, Error> {
+        Ok(self.iter().cloned())
+    }
+}
+
+impl ToSocketAddrs for Vec<SocketAddr> {
+    type Iter = vec::IntoIter<SocketAddr>;
+
+    //=======================================================================
+    fn to_socket_addrs (&self) -> Result<vec::IntoIter<SocketAddr>, Error> {
+        Ok(self.clone().into_iter())
+    }
+}
+
+impl<'a, T: ToSocketAddrs + ?Sized> ToSocketAddrs for &'a T {
+    type Iter = T::Iter;
+
+    //=======================================================================
+    fn to_socket_addrs (&self) -> Result<T::Iter, Error> {
+        (**self).to_socket_addrs()
+    }
+}
+
+
+/****************************************************************************
+*
+*   Private functions
+*
+***/
+
+//===========================================================================
+fn invalid_input () -> Error {
+    Error::new(ErrorKind::InvalidInput, "Invalid socket address")
+}
+
+//===========================================================================
+fn is_global_v4 (ip: &Ipv4Addr) -> bool {
+    let o = ip.octets();
+    !(o[0] == 0                                     // "this network"
+        || o[0] == 10                               // private
+        || o[0] == 100 && o[1] & 0xc0 == 64         // shared address space
+        || o[0] == 127                              // loopback
+        || o[0] == 169 && o[1] == 254               // link local
+        || o[0] == 172 && o[1] & 0xf0 == 16         // private
+        || o[0] == 192 && o[1] == 0 && o[2] == 0    // protocol assignments
+            && o[3] != 9 && o[3] != 10
+        || o[0] == 192 && o[1] == 0 && o[2] == 2    // documentation
+        || o[0] == 192 && o[1] == 168               // private
+        || o[0] == 198 && o[1] & 0xfe == 18         // benchmarking
+        || o[0] == 198 && o[1] == 51 && o[2] == 100 // documentation
+        || o[0] == 203 && o[1] == 0 && o[2] == 113  // documentation
+        || o[0] >= 240)                             // reserved and broadcast
+}
+
+//===========================================================================
+fn is_global_v6 (ip: &Ipv6Addr) -> bool {
+    let s = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || s[0..5] == [0, 0, 0, 0, 0] && s[5] == 0xffff         // IPv4-mapped
+        || s[0] == 0x64 && s[1] == 0xff9b && s[2] == 1          // local translation
+        || s[0] == 0x100 && s[1..4] == [0, 0, 0]                // discard only
+        || s[0] == 0x2001 && s[1] < 0x200 && !is_global_v6_assignment(&s)
+        || s[0] == 0x2001 && s[1] == 0xdb8                      // documentation
+        || s[0] & 0xfe00 == 0xfc00                              // unique local
+        || s[0] & 0xffc0 == 0xfe80)                             // link local
+}
+
+//===========================================================================
+// Globally reachable exceptions within the 2001::/23 protocol assignments
+fn is_global_v6_assignment (s: &[u16; 8]) -> bool {
+    s[1] == 1 && s[2..7] == [0, 0, 0, 0, 0] && (s[7] == 1 || s[7] == 2)    // PCP and TURN anycast
+        || s[1] == 3                                                        // AMT
+        || s[1] == 4 && s[2] == 0x112                                       // AS112
+        || s[1] >= 0x20 && s[1] < 0x30                                      // ORCHIDv2
+}
+
+//===========================================================================
+fn ipv4_mapped (ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff, high, low] => Some(Ipv4Addr::new(
+            (high >> 8) as u8,
+            high as u8,
+            (low >> 8) as u8,
+            low as u8
+        )),
+        _ => None,
+    }
+}
+
+//===========================================================================
+fn resolve<I> (result: io::Result<I>) -> Result<vec::IntoIter<SocketAddr>, Error>
+    where I: Iterator<Item=net::SocketAddr>
+{
+    match result {
+        Ok(addrs) => Ok(addrs.map(SocketAddr::from).collect::<Vec<_>>().into_iter()),
+        Err(e) => match e.raw_os_error() {
+            Some(code) => Err(Error::from_os_error_code(code)),
+            None => Err(Error::new(ErrorKind::Unknown, e)),
+        },
+    }
+}
+
+//===========================================================================
+// Parses an IPv4 literal or an unbracketed IPv6 literal with an optional
+// "%scope" suffix naming an interface by index or by name
+fn parse_host (host: &str, port: u16) -> Option<SocketAddr> {
+    if let Ok(ip) = Ipv4Addr::from_str(host) {
+        return Some(SocketAddr::V4(SocketAddrV4::new(ip, port)));
+    }
+
+    let (ip, scope_id) = match host.find('%') {
+        Some(i) => match u32::from_str(&host[i + 1..]) {
+            Ok(scope_id) => (&host[..i], scope_id),
+            Err(..) => match interface_index(&host[i + 1..]) {
+                Some(scope_id) => (&host[..i], scope_id),
+                None => return None,
+            },
+        },
+        None => (host, 0),
+    };
+
+    match Ipv6Addr::from_str(ip) {
+        Ok(ip) => Some(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id))),
+        Err(..) => None,
+    }
+}
+
+//===========================================================================
+fn interface_index (name: &str) -> Option<u32> {
+    let name = match CString::new(name) {
+        Ok(name) => name,
+        Err(..) => return None,
+    };
+
+    match unsafe { sys::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+//===========================================================================
+// Parses "a.b.c.d:port" or "[v6%scope]:port"
+fn parse_socket_addr (s: &str) -> Option<SocketAddr> {
+    let (host, port) = if s.starts_with('[') {
+        let end = match s.find(']') {
+            Some(end) => end,
+            None => return None,
+        };
+        if !s[end + 1..].starts_with(':') {
+            return None;
+        }
+        (&s[1..end], &s[end + 2..])
+    }
+    else {
+        match s.rfind(':') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => return None,
+        }
+    };
+
+    let port = match u16::from_str(port) {
+        Ok(port) => port,
+        Err(..) => return None,
+    };
+
+    // Brackets are required around IPv6 hosts and not allowed otherwise
+    match parse_host(host, port) {
+        Some(SocketAddr::V6(a)) if s.starts_with('[') => Some(SocketAddr::V6(a)),
+        Some(SocketAddr::V4(a)) if !s.starts_with('[') => Some(SocketAddr::V4(a)),
+        _ => None,
+    }
+}
+
+
+/****************************************************************************
+*
+*   Tests
+*
+***/
+
+#[cfg(test)]
+mod tests {
+    use std::net;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    use sys;
+    use super::*;
+    use super::{interface_index, parse_socket_addr};
+
+    //=======================================================================
+    fn v4 (a: u8, b: u8, c: u8, d: u8, port: u16) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), port))
+    }
+
+    //=======================================================================
+    fn v6 (segments: [u16; 8], port: u16, scope_id: u32) -> SocketAddr {
+        let s = segments;
+        let ip = Ipv6Addr::new(s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7]);
+        SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id))
+    }
+
+    //=======================================================================
+    fn one<A: ToSocketAddrs + ?Sized> (addrs: &A) -> SocketAddr {
+        let addrs: Vec<SocketAddr> = addrs.to_socket_addrs().unwrap().collect();
+        assert_eq!(addrs.len(), 1);
+        addrs[0]
+    }
+
+    //=======================================================================
+    #[test]
+    fn parse_v4 () {
+        assert_eq!(parse_socket_addr("127.0.0.1:8080"), Some(v4(127, 0, 0, 1, 8080)));
+        assert_eq!(parse_socket_addr("0.0.0.0:0"), Some(v4(0, 0, 0, 0, 0)));
+        assert_eq!(parse_socket_addr("255.255.255.255:65535"), Some(v4(255, 255, 255, 255, 65535)));
+    }
+
+    //=======================================================================
+    #[test]
+    fn parse_v6 () {
+        assert_eq!(parse_socket_addr("[::1]:80"), Some(v6([0, 0, 0, 0, 0, 0, 0, 1], 80, 0)));
+        assert_eq!(parse_socket_addr("[::]:0"), Some(v6([0; 8], 0, 0)));
+        assert_eq!(
+            parse_socket_addr("[2001:db8::8:800:200c:417a]:443"),
+            Some(v6([0x2001, 0xdb8, 0, 0, 0x8, 0x800, 0x200c, 0x417a], 443, 0))
+        );
+        assert_eq!(
+            parse_socket_addr("[::ffff:10.0.0.1]:22"),
+            Some(v6([0, 0, 0, 0, 0, 0xffff, 0x0a00, 0x0001], 22, 0))
+        );
+    }
+
+    //=======================================================================
+    #[test]
+    fn parse_v6_scope () {
+        assert_eq!(
+            parse_socket_addr("[fe80::1%3]:443"),
+            Some(v6([0xfe80, 0, 0, 0, 0, 0, 0, 1], 443, 3))
+        );
+        assert_eq!(
+            parse_socket_addr("[fe80::1%4294967295]:1"),
+            Some(v6([0xfe80, 0, 0, 0, 0, 0, 0, 1], 1, 4294967295))
+        );
+        assert_eq!(parse_socket_addr("[fe80::1%]:443"), None);
+        assert_eq!(parse_socket_addr("[fe80::1%-1]:443"), None);
+        assert_eq!(parse_socket_addr("[fe80::1%4294967296]:443"), None);
+        assert_eq!(parse_socket_addr("[fe80::1%no-such-interface]:443"), None);
+    }
+
+    //=======================================================================
+    #[test]
+    fn parse_v6_scope_name () {
+        let index = interface_index("loopback_0").unwrap();
+        assert_eq!(
+            parse_socket_addr("[fe80::1%loopback_0]:80"),
+            Some(v6([0xfe80, 0, 0, 0, 0, 0, 0, 1], 80, index))
+        );
+    }
+
+    //=======================================================================
+    #[test]
+    fn sockaddr_round_trip () {
+        let ip = Ipv6Addr::new(0xfe80, 0, 0, 0, 0x1234, 0x5678, 0x9abc, 0xdef0);
+        let addr = SocketAddrV6::new(ip, 8080, 0x12345, 9);
+        let raw = sys::sockaddr_in6::from_addr(addr);
+        let storage = sys::sockaddr_storage::from_raw(
+            &raw as *const _ as sys::PVOID,
+            ::std::mem::size_of_val(&raw) as i32
+        );
+        assert_eq!(storage.get_addr(), Some(SocketAddr::V6(addr)));
+
+        let addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 53);
+        let raw = sys::sockaddr_in::from_addr(addr);
+        let storage = sys::sockaddr_storage::from_raw(
+            &raw as *const _ as sys::PVOID,
+            ::std::mem::size_of_val(&raw) as i32
+        );
+        assert_eq!(storage.get_addr(), Some(SocketAddr::V4(addr)));
+
+        // v4-mapped addresses stay IPv6 so they can be sent to again
+        let ip = Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0xc0a8, 0x0102);
+        let mapped = SocketAddrV6::new(ip, 53, 0, 0);
+        let raw = sys::sockaddr_in6::from_addr(mapped);
+        let storage = sys::sockaddr_storage::from_raw(
+            &raw as *const _ as sys::PVOID,
+            ::std::mem::size_of_val(&raw) as i32
+        );
+        assert_eq!(storage.get_addr(), Some(SocketAddr::V6(mapped)));
+    }
+
+    //=======================================================================
+    #[test]
+    fn canonical () {
+        let v4 = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 53));
+        let ip = Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0xc0a8, 0x0102);
+        assert_eq!(SocketAddr::V6(SocketAddrV6::new(ip, 53, 0, 0)).to_canonical(), v4);
+        assert_eq!(v4.to_canonical(), v4);
+
+        let v6 = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 53, 0, 0));
+        assert_eq!(v6.to_canonical(), v6);
+    }
+
+    //=======================================================================
+    #[test]
+    fn parse_invalid () {
+        let invalid = [
+            "",
+            ":",
+            "127.0.0.1",
+            "127.0.0.1:",
+            "127.0.0.1:65536",
+            "127.0.0.1:-1",
+            "127.0.0.1:http",
+            "127.0.0.256:80",
+            "127.0.0:80",
+            " 127.0.0.1:80",
+            "127.0.0.1:80 ",
+            "::1:80",
+            "[::1]",
+            "[::1]80",
+            "[::1:80",
+            "::1]:80",
+            "[127.0.0.1]:80",
+            "[::1]:",
+            "[::g]:80",
+            "[1:2:3:4:5:6:7:8:9]:80",
+        ];
+
+        for s in invalid.iter() {
+            assert_eq!(parse_socket_addr(s), None, "{:?}", s);
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn str_literals () {
+        assert_eq!(one("10.1.2.3:53"), v4(10, 1, 2, 3, 53));
+        assert_eq!(one("[fe80::2%7]:53"), v6([0xfe80, 0, 0, 0, 0, 0, 0, 2], 53, 7));
+        assert_eq!(one(&String::from("10.1.2.3:53")), v4(10, 1, 2, 3, 53));
+    }
+
+    //=======================================================================
+    #[test]
+    fn str_invalid_port () {
+        assert!("localhost:99999".to_socket_addrs().is_err());
+        assert!("127.0.0.1:99999".to_socket_addrs().is_err());
+    }
+
+    //=======================================================================
+    #[test]
+    fn tuple_literals () {
+        assert_eq!(one(&("192.168.0.1", 8080)), v4(192, 168, 0, 1, 8080));
+        assert_eq!(one(&("::1", 8080)), v6([0, 0, 0, 0, 0, 0, 0, 1], 8080, 0));
+        assert_eq!(one(&("fe80::1%2", 1)), v6([0xfe80, 0, 0, 0, 0, 0, 0, 1], 1, 2));
+
+        let ip = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        assert_eq!(one(&(ip, 5)), v4(1, 2, 3, 4, 5));
+    }
+
+    //=======================================================================
+    #[test]
+    fn tuple_localhost () {
+        let addrs: Vec<SocketAddr> = ("localhost", 80).to_socket_addrs().unwrap().collect();
+        assert!(!addrs.is_empty());
+        for addr in addrs {
+            assert_eq!(addr.port(), 80);
+            match addr.ip() {
+                IpAddr::V4(ip) => assert_eq!(ip.octets()[0], 127),
+                IpAddr::V6(ip) => assert_eq!(ip, Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+            }
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn std_and_collections () {
+        let std_addr = net::SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 53));
+        assert_eq!(one(&std_addr), v4(1, 1, 1, 1, 53));
+
+        let addrs = vec![v4(1, 0, 0, 1, 1), v6([0, 0, 0, 0, 0, 0, 0, 1], 2, 0)];
+        let from_vec: Vec<SocketAddr> = addrs.to_socket_addrs().unwrap().collect();
+        let from_slice: Vec<SocketAddr> = (&addrs[..]).to_socket_addrs().unwrap().collect();
+        assert_eq!(from_vec, addrs);
+        assert_eq!(from_slice, addrs);
+    }
+
+    //=======================================================================
+    #[test]
+    fn std_round_trip () {
+        let addrs = [v4(10, 0, 0, 1, 80), v6([0xfe80, 0, 0, 0, 0, 0, 0, 1], 443, 7)];
+        for addr in addrs.iter() {
+            let std_addr = net::SocketAddr::from(*addr);
+            assert_eq!(SocketAddr::from(std_addr), *addr);
+            assert_eq!(std_addr.to_string(), addr.to_string());
+
+            let ip = addr.ip();
+            assert_eq!(IpAddr::from(net::IpAddr::from(ip)), ip);
+            assert_eq!(net::IpAddr::from(ip), std_addr.ip());
+        }
+
+        assert_eq!(IpAddr::from(Ipv4Addr::new(1, 2, 3, 4)), IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+        assert_eq!(SocketAddr::from(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 5)), v4(1, 2, 3, 4, 5));
+    }
+
+    //=======================================================================
+    #[test]
+    fn from_str () {
+        for s in ["127.0.0.1:80", "[::1]:8080", "[fe80::1%3]:1"].iter() {
+            let addr: SocketAddr = s.parse().unwrap();
+            let std_addr: net::SocketAddr = s.parse().unwrap();
+            assert_eq!(addr, SocketAddr::from(std_addr));
+            assert_eq!(addr.to_string(), std_addr.to_string());
+        }
+
+        for s in ["1.2.3.4", "::ffff:1.2.3.4", "2001:db8::1"].iter() {
+            let ip: IpAddr = s.parse().unwrap();
+            let std_ip: net::IpAddr = s.parse().unwrap();
+            assert_eq!(ip, IpAddr::from(std_ip));
+            assert_eq!(ip.to_string(), std_ip.to_string());
+        }
+
+        assert!("localhost:80".parse::<SocketAddr>().is_err());
+        assert!("::1:80".parse::<SocketAddr>().is_err());
+        assert!("1.2.3".parse::<IpAddr>().is_err());
+    }
+
+    //=======================================================================
+    #[test]
+    fn ip_properties () {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        assert!(ip("0.0.0.0").is_unspecified() && ip("::").is_unspecified());
+        assert!(ip("127.1.2.3").is_loopback() && ip("::1").is_loopback());
+        assert!(ip("224.0.0.1").is_multicast() && ip("ff02::1").is_multicast());
+        assert!(!ip("8.8.8.8").is_loopback() && !ip("8.8.8.8").is_multicast());
+
+        for s in ["8.8.8.8", "1.1.1.1", "192.0.0.9", "2606:4700::1111", "2001:4:112::1"].iter() {
+            assert!(ip(s).is_global(), "{}", s);
+        }
+        for s in [
+            "0.0.0.0", "10.1.1.1", "100.64.0.1", "127.0.0.1", "169.254.1.1", "172.16.0.1",
+            "192.0.0.1", "192.0.2.1", "192.168.1.1", "198.18.0.1", "203.0.113.1",
+            "255.255.255.255", "::", "::1", "::ffff:8.8.8.8", "2001:db8::1", "fd00::1",
+            "fe80::1", "100::1",
+        ].iter() {
+            assert!(!ip(s).is_global(), "{}", s);
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn family () {
+        assert_eq!(v4(1, 2, 3, 4, 5).family(), AddrFamily::V4);
+        assert_eq!(v6([0, 0, 0, 0, 0, 0, 0, 1], 0, 0).ip().family(), AddrFamily::V6);
+        assert!(AddrFamily::V4 != AddrFamily::V6);
+    }
+}
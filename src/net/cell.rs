@@ -0,0 +1,113 @@
+/****************************************************************************
+*
+*   net/cell.rs
+*   ioq
+*
+*   Copyright 2015 Tyler Cole
+*
+***/
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+/****************************************************************************
+*
+*   AddrCell
+*
+***/
+
+const ADDR_INITIAL: usize = 0;
+const ADDR_CLAIMED: usize = 1;
+const ADDR_SET: usize = 2;
+
+// Address that starts out with a known value and may be replaced once, so
+// that readers never need to lock
+pub struct AddrCell<T> {
+    initial: T,
+    state: AtomicUsize,
+    value: UnsafeCell<T>,
+}
+
+// value is only written by the thread that moves state to ADDR_CLAIMED, and
+// only read after state reaches ADDR_SET
+unsafe impl<T: Send + Sync> Sync for AddrCell<T> {}
+
+impl<T: Clone> AddrCell<T> {
+    //=======================================================================
+    pub fn new (initial: T) -> AddrCell<T> {
+        AddrCell {
+            initial: initial.clone(),
+            state: AtomicUsize::new(ADDR_INITIAL),
+            value: UnsafeCell::new(initial),
+        }
+    }
+
+    //=======================================================================
+    pub fn get (&self) -> T {
+        if self.state.load(Ordering::Acquire) == ADDR_SET {
+            unsafe { (*self.value.get()).clone() }
+        }
+        else {
+            self.initial.clone()
+        }
+    }
+
+    //=======================================================================
+    // Returns false if the address has already been replaced
+    pub fn set (&self, addr: T) -> bool {
+        if !self.claim(addr) {
+            return false;
+        }
+
+        self.publish();
+        true
+    }
+
+    //=======================================================================
+    // Stores addr without exposing it until publish, so that a failed
+    // operation can release the cell. Returns false if the address has
+    // already been claimed.
+    pub fn claim (&self, addr: T) -> bool {
+        let claimed = self.state.compare_exchange(
+            ADDR_INITIAL,
+            ADDR_CLAIMED,
+            Ordering::Acquire,
+            Ordering::Relaxed
+        );
+        if claimed.is_err() {
+            return false;
+        }
+
+        unsafe { *self.value.get() = addr };
+        true
+    }
+
+    //=======================================================================
+    pub fn publish (&self) {
+        let _ = self.state.compare_exchange(
+            ADDR_CLAIMED,
+            ADDR_SET,
+            Ordering::Release,
+            Ordering::Relaxed
+        );
+    }
+
+    //=======================================================================
+    pub fn release (&self) {
+        let _ = self.state.compare_exchange(
+            ADDR_CLAIMED,
+            ADDR_INITIAL,
+            Ordering::Release,
+            Ordering::Relaxed
+        );
+    }
+}
+
+impl<T: Clone + fmt::Debug> fmt::Debug for AddrCell<T> {
+    //=======================================================================
+    fn fmt (&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.get(), fmt)
+    }
+}
@@ -0,0 +1,1563 @@
+/****************************************************************************
+*
+*   net/dns.rs
+*   ioq
+*
+*   Copyright 2015 Tyler Cole
+*
+***/
+
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Read;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use sys;
+use queue;
+use error::{Error, ErrorKind};
+
+use super::socket::Socket;
+use super::addr::{IpAddr, SocketAddr, ToSocketAddrs};
+
+
+/****************************************************************************
+*
+*   Constants
+*
+***/
+
+const PORT: u16 = 53;
+
+const HEADER_BYTES: usize = 12;
+const UDP_MESSAGE_BYTES: usize = 4096;
+const TCP_CHUNK_BYTES: usize = 4096;
+const MAX_NAME_BYTES: usize = 255;
+const MAX_LABEL_BYTES: usize = 63;
+const MAX_POINTERS: usize = 64;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+
+const CLASS_IN: u16 = 1;
+
+const RCODE_NXDOMAIN: u8 = 3;
+
+const ADAPTER_BUFFER_BYTES: u32 = 16 * 1024;
+
+
+/****************************************************************************
+*
+*   RecordType
+*
+***/
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum RecordType {
+    A,
+    AAAA,
+    SRV,
+    TXT,
+}
+
+impl RecordType {
+    //=======================================================================
+    pub fn code (&self) -> u16 {
+        match *self {
+            RecordType::A => 1,
+            RecordType::TXT => 16,
+            RecordType::AAAA => 28,
+            RecordType::SRV => 33,
+        }
+    }
+}
+
+
+/****************************************************************************
+*
+*   Record
+*
+***/
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    SRV { priority: u16, weight: u16, port: u16, target: String },
+    TXT(Vec<Vec<u8>>),
+}
+
+impl RecordData {
+    //=======================================================================
+    pub fn record_type (&self) -> RecordType {
+        match *self {
+            RecordData::A(..) => RecordType::A,
+            RecordData::AAAA(..) => RecordType::AAAA,
+            RecordData::SRV { .. } => RecordType::SRV,
+            RecordData::TXT(..) => RecordType::TXT,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Record {
+    pub name: String,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+
+/****************************************************************************
+*
+*   Question
+*
+***/
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Question {
+    pub name: String,
+    pub record_type: RecordType,
+}
+
+
+/****************************************************************************
+*
+*   Config
+*
+***/
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub servers: Vec<SocketAddr>,
+    pub timeout: Duration,
+    pub attempts: u32,
+}
+
+impl Config {
+    //=======================================================================
+    pub fn new (servers: Vec<SocketAddr>) -> Config {
+        Config {
+            servers: servers,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+        }
+    }
+
+    //=======================================================================
+    // Reads the nameserver lines and the timeout and attempts options of a
+    // resolv.conf file; everything else is ignored
+    pub fn parse (text: &str) -> Config {
+        let mut config = Config::new(Vec::new());
+
+        for line in text.lines() {
+            let line = match line.find(|c| c == '#' || c == ';') {
+                Some(i) => &line[..i],
+                None => line,
+            };
+
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") => {
+                    let addr = words.next().and_then(|host| {
+                        (host, PORT).to_socket_addrs().ok().and_then(|mut a| a.next())
+                    });
+                    if let Some(addr) = addr {
+                        config.servers.push(addr);
+                    }
+                },
+                Some("options") => {
+                    for option in words {
+                        let mut parts = option.splitn(2, ':');
+                        let name = parts.next().unwrap();
+                        let value = parts.next().and_then(|v| v.parse::<u32>().ok());
+                        match (name, value) {
+                            ("timeout", Some(v)) => config.timeout = Duration::from_secs(v as u64),
+                            ("attempts", Some(v)) => config.attempts = v,
+                            _ => {},
+                        }
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        config
+    }
+
+    //=======================================================================
+    pub fn from_resolv_conf<P: AsRef<Path>> (path: P) -> Result<Config, Error> {
+        let mut text = String::new();
+        let result = File::open(path).and_then(|mut f| f.read_to_string(&mut text));
+
+        match result {
+            Ok(..) => Ok(Config::parse(&text)),
+            Err(e) => match e.raw_os_error() {
+                Some(code) => Err(Error::from_os_error_code(code)),
+                None => Err(Error::new(ErrorKind::Unknown, e)),
+            },
+        }
+    }
+
+    //=======================================================================
+    // Uses the DNS servers configured on the network adapters that are up
+    pub fn system () -> Result<Config, Error> {
+        let flags = sys::GAA_FLAG_SKIP_UNICAST
+            | sys::GAA_FLAG_SKIP_ANYCAST
+            | sys::GAA_FLAG_SKIP_MULTICAST
+            | sys::GAA_FLAG_SKIP_FRIENDLY_NAME;
+
+        // The list is copied into the buffer, which grows until it fits
+        let mut buffer: Vec<u64> = Vec::new();
+        let mut bytes: u32 = ADAPTER_BUFFER_BYTES;
+        loop {
+            buffer.resize((bytes as usize + 7) / 8, 0);
+            let code = unsafe {
+                sys::GetAdaptersAddresses(
+                    sys::AF_UNSPEC as u32,
+                    flags,
+                    ptr::null_mut(),
+                    buffer.as_mut_ptr() as *mut sys::IP_ADAPTER_ADDRESSES,
+                    &mut bytes
+                )
+            };
+            match code {
+                0 => break,
+                sys::ERROR_BUFFER_OVERFLOW => continue,
+                code => return Err(Error::from_os_error_code(code as i32)),
+            }
+        }
+
+        let mut config = Config::new(Vec::new());
+        let mut adapter = buffer.as_ptr() as *const sys::IP_ADAPTER_ADDRESSES;
+        while !adapter.is_null() {
+            let entry = unsafe { &*adapter };
+            adapter = entry.Next;
+            if entry.OperStatus != sys::IF_OPER_STATUS_UP {
+                continue;
+            }
+
+            let mut server = entry.FirstDnsServerAddress as *const sys::IP_ADAPTER_DNS_SERVER_ADDRESS;
+            while !server.is_null() {
+                let entry = unsafe { &*server };
+                server = entry.Next;
+
+                let addr = sys::sockaddr_storage::from_raw(
+                    entry.Address.lpSockaddr,
+                    entry.Address.iSockaddrLength
+                ).get_addr();
+                if let Some(addr) = addr {
+                    let addr = SocketAddr::new(addr.ip(), PORT);
+                    if !is_placeholder(&addr) && !config.servers.contains(&addr) {
+                        config.servers.push(addr);
+                    }
+                }
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+
+/****************************************************************************
+*
+*   Client
+*
+***/
+
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<ClientInner>,
+}
+
+impl Client {
+    //=======================================================================
+    pub fn new (config: Config, queue: queue::Queue) -> Client {
+        Client {
+            inner: Arc::new(ClientInner {
+                queue: queue,
+                config: config,
+                cache: Mutex::new(Cache::new()),
+                random: RandomState::new(),
+                counter: AtomicUsize::new(0),
+            })
+        }
+    }
+
+    //=======================================================================
+    // Completes with Event::DnsAnswer. Answers are cached for their TTL.
+    pub fn query (&self, name: &str, record_type: RecordType) -> Result<(), Error> {
+        let question = Question {
+            name: name.trim_right_matches('.').to_lowercase(),
+            record_type: record_type,
+        };
+
+        // Answer from the cache
+        let cached = self.inner.cache.lock().unwrap().get(&question, Instant::now());
+        if let Some(records) = cached {
+            let context = Box::new(AnswerContext {
+                question: question,
+                result: Ok(records),
+            });
+            return queue::post(&self.inner.queue, context);
+        }
+
+        if self.inner.config.servers.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "No DNS servers configured"));
+        }
+
+        // Build query
+        let id = self.inner.next_id();
+        let message = match encode_query(id, &question.name, record_type) {
+            Ok(message) => message,
+            Err(error) => return Err(error),
+        };
+
+        let lookup = Arc::new(Lookup {
+            client: self.clone(),
+            question: question,
+            id: id,
+            message: message,
+            tries: AtomicUsize::new(0),
+        });
+
+        Lookup::send_udp(&lookup)
+    }
+
+    //=======================================================================
+    pub fn clear_cache (&self) {
+        self.inner.cache.lock().unwrap().clear();
+    }
+}
+
+struct ClientInner {
+    queue: queue::Queue,
+    config: Config,
+    cache: Mutex<Cache>,
+    random: RandomState,
+    counter: AtomicUsize,
+}
+
+impl ClientInner {
+    //=======================================================================
+    fn next_id (&self) -> u16 {
+        let mut hasher = self.random.build_hasher();
+        hasher.write_usize(self.counter.fetch_add(1, Ordering::SeqCst));
+        hasher.finish() as u16
+    }
+}
+
+
+/****************************************************************************
+*
+*   Cache
+*
+***/
+
+struct Cache {
+    entries: HashMap<Question, (Instant, Vec<Record>)>,
+}
+
+impl Cache {
+    //=======================================================================
+    fn new () -> Cache {
+        Cache {
+            entries: HashMap::new(),
+        }
+    }
+
+    //=======================================================================
+    // Returns the cached records with their TTLs reduced by the time spent
+    // in the cache
+    fn get (&mut self, question: &Question, now: Instant) -> Option<Vec<Record>> {
+        let expired = match self.entries.get(question) {
+            Some(&(expires, ..)) => expires <= now,
+            None => return None,
+        };
+
+        if expired {
+            self.entries.remove(question);
+            return None;
+        }
+
+        let &(expires, ref records) = self.entries.get(question).unwrap();
+        let remaining = (expires - now).as_secs() as u32;
+        Some(records.iter().map(|r| {
+            Record {
+                name: r.name.clone(),
+                ttl: remaining,
+                data: r.data.clone(),
+            }
+        }).collect())
+    }
+
+    //=======================================================================
+    fn insert (&mut self, question: Question, records: &[Record], now: Instant) {
+        let ttl = match records.iter().map(|r| r.ttl).min() {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => return,
+        };
+
+        let expires = now + Duration::from_secs(ttl as u64);
+        self.entries.insert(question, (expires, records.to_vec()));
+    }
+
+    //=======================================================================
+    fn clear (&mut self) {
+        self.entries.clear();
+    }
+}
+
+
+/****************************************************************************
+*
+*   Lookup
+*
+***/
+
+struct Lookup {
+    client: Client,
+    question: Question,
+    id: u16,
+    message: Vec<u8>,
+    tries: AtomicUsize,
+}
+
+impl Lookup {
+    //=======================================================================
+    fn queue (&self) -> &queue::Queue { &self.client.inner.queue }
+    fn config (&self) -> &Config { &self.client.inner.config }
+
+    //=======================================================================
+    // Sends the query to the next server over UDP, moving on to the one
+    // after if submitting fails
+    fn send_udp (lookup: &Arc<Lookup>) -> Result<(), Error> {
+        let servers = &lookup.config().servers;
+        let max_tries = servers.len() * lookup.config().attempts as usize;
+
+        let mut last_error = None;
+        loop {
+            let tries = lookup.tries.fetch_add(1, Ordering::SeqCst);
+            if tries >= max_tries {
+                return Err(last_error.unwrap_or_else(Error::timed_out));
+            }
+
+            let server = servers[tries % servers.len()];
+            match Lookup::send_udp_to(lookup, server) {
+                Ok(()) => return Ok(()),
+                Err(error) => last_error = Some(error),
+            }
+        }
+    }
+
+    //=======================================================================
+    // A response must come back with the query's id and question; anything
+    // else is stray or forged
+    fn is_response (&self, response: &Response) -> bool {
+        if response.id != self.id || response.questions.len() != 1 {
+            return false;
+        }
+
+        let (ref name, code, class) = response.questions[0];
+        name.eq_ignore_ascii_case(&self.question.name)
+            && code == self.question.record_type.code()
+            && class == CLASS_IN
+    }
+
+    //=======================================================================
+    fn send_udp_to (lookup: &Arc<Lookup>, server: SocketAddr) -> Result<(), Error> {
+        let socket = match open(Socket::new_udp(server.family()), server, lookup.queue()) {
+            Ok(socket) => Arc::new(socket),
+            Err(error) => return Err(error),
+        };
+
+        let mut buffer = lookup.message.clone().into_boxed_slice();
+        let buf = sys::WSABUF::new(&mut buffer[..]);
+
+        let state = Box::new(queue::State::new(Box::new(UdpSendContext {
+            lookup: lookup.clone(),
+            socket: socket.clone(),
+            server: server,
+            buffer: buffer,
+        })));
+
+        socket.send_to_ex(buf, server, state)
+    }
+
+    //=======================================================================
+    // Receives until expires, which stays fixed while ignored datagrams are
+    // skipped so that they cannot hold the lookup open
+    fn receive_udp (
+        lookup: &Arc<Lookup>,
+        socket: Arc<Socket>,
+        server: SocketAddr,
+        expires: Instant
+    ) -> Result<(), Error> {
+        let mut context = Box::new(UdpReceiveContext {
+            lookup: lookup.clone(),
+            socket: socket.clone(),
+            server: server,
+            expires: expires,
+            buffer: vec![0u8; UDP_MESSAGE_BYTES].into_boxed_slice(),
+            addr: sys::sockaddr_storage::new(),
+            addr_bytes: 0,
+        });
+
+        let buf = sys::WSABUF::new(&mut context.buffer[..]);
+        context.addr_bytes = ::std::mem::size_of::<sys::sockaddr_storage>() as i32;
+        let addr = &mut context.addr as *mut _;
+        let addr_bytes = &mut context.addr_bytes as *mut _;
+
+        let now = Instant::now();
+        let timeout = if expires > now { expires - now } else { Duration::from_millis(0) };
+
+        let mut state = Box::new(queue::State::new(context));
+        queue::set_deadline(lookup.queue(), &mut state, socket.handle(), timeout);
+        socket.receive_from_ex(buf, addr, addr_bytes, state)
+    }
+
+    //=======================================================================
+    // Repeats the query over TCP after a truncated UDP response
+    fn send_tcp (lookup: &Arc<Lookup>, server: SocketAddr) -> Result<(), Error> {
+        let socket = match open(Socket::new_from_family(server.family()), server, lookup.queue()) {
+            Ok(socket) => Arc::new(socket),
+            Err(error) => return Err(error),
+        };
+
+        // Messages over TCP are prefixed with their length
+        let bytes = lookup.message.len();
+        let mut buffer = Vec::with_capacity(2 + bytes);
+        buffer.push((bytes >> 8) as u8);
+        buffer.push(bytes as u8);
+        buffer.extend_from_slice(&lookup.message);
+        let buffer = buffer.into_boxed_slice();
+        let data = buffer.as_ptr() as sys::PVOID;
+
+        let mut state = Box::new(queue::State::new(Box::new(TcpConnectContext {
+            lookup: lookup.clone(),
+            socket: socket.clone(),
+            buffer: buffer,
+        })));
+
+        queue::set_deadline(lookup.queue(), &mut state, socket.handle(), lookup.config().timeout);
+        socket.connect_ex(server, data, (2 + bytes) as u32, state)
+    }
+
+    //=======================================================================
+    fn receive_tcp (lookup: &Arc<Lookup>, socket: Arc<Socket>, received: Vec<u8>)
+        -> Result<(), Error>
+    {
+        let mut buffer = vec![0u8; TCP_CHUNK_BYTES].into_boxed_slice();
+        let buf = sys::WSABUF::new(&mut buffer[..]);
+
+        let mut state = Box::new(queue::State::new(Box::new(TcpReceiveContext {
+            lookup: lookup.clone(),
+            socket: socket.clone(),
+            buffer: buffer,
+            received: received,
+        })));
+
+        queue::set_deadline(lookup.queue(), &mut state, socket.handle(), lookup.config().timeout);
+        socket.receive_ex(buf, state)
+    }
+
+    //=======================================================================
+    // Tries the next server, or reports error once all tries are used up
+    fn retry (lookup: &Arc<Lookup>, error: Error) -> Option<queue::Event> {
+        match Lookup::send_udp(lookup) {
+            Ok(()) => None,
+            Err(..) => lookup.answer(Err(error)),
+        }
+    }
+
+    //=======================================================================
+    fn complete (lookup: &Arc<Lookup>, response: Response) -> Option<queue::Event> {
+        if response.rcode == RCODE_NXDOMAIN {
+            return lookup.answer(Err(Error::new(ErrorKind::NotFound, "Name does not exist")));
+        }
+        if response.rcode != 0 {
+            let message = format!("DNS server failure (rcode {})", response.rcode);
+            return lookup.answer(Err(Error::new(ErrorKind::Unknown, message)));
+        }
+
+        // Keep only records of the requested type, skipping aliases
+        let records: Vec<Record> = response.records.into_iter().filter(|r| {
+            r.data.record_type() == lookup.question.record_type
+        }).collect();
+
+        lookup.client.inner.cache.lock().unwrap().insert(
+            lookup.question.clone(),
+            &records,
+            Instant::now()
+        );
+        lookup.answer(Ok(records))
+    }
+
+    //=======================================================================
+    fn answer (&self, result: Result<Vec<Record>, Error>) -> Option<queue::Event> {
+        Some(queue::Event::DnsAnswer(self.question.clone(), result))
+    }
+}
+
+
+/****************************************************************************
+*
+*   AnswerContext
+*
+***/
+
+struct AnswerContext {
+    question: Question,
+    result: Result<Vec<Record>, Error>,
+}
+
+impl queue::Context for AnswerContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, _: u32) -> Option<queue::Event> {
+        Some(queue::Event::DnsAnswer(self.question, self.result))
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        Some(queue::Event::DnsAnswer(self.question, Err(error)))
+    }
+}
+
+
+/****************************************************************************
+*
+*   UdpSendContext
+*
+***/
+
+struct UdpSendContext {
+    lookup: Arc<Lookup>,
+    socket: Arc<Socket>,
+    server: SocketAddr,
+    buffer: Box<[u8]>,
+}
+
+impl queue::Context for UdpSendContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, bytes: u32) -> Option<queue::Event> {
+        if bytes as usize != self.buffer.len() {
+            let error = Error::new(ErrorKind::InvalidData, "DNS query not fully sent");
+            return Lookup::retry(&self.lookup, error);
+        }
+
+        let expires = Instant::now() + self.lookup.config().timeout;
+        match Lookup::receive_udp(&self.lookup, self.socket, self.server, expires) {
+            Ok(()) => None,
+            Err(error) => Lookup::retry(&self.lookup, error),
+        }
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        Lookup::retry(&self.lookup, error)
+    }
+}
+
+
+/****************************************************************************
+*
+*   UdpReceiveContext
+*
+***/
+
+struct UdpReceiveContext {
+    lookup: Arc<Lookup>,
+    socket: Arc<Socket>,
+    server: SocketAddr,
+    expires: Instant,
+    buffer: Box<[u8]>,
+    addr: sys::sockaddr_storage,
+    addr_bytes: i32,
+}
+
+impl queue::Context for UdpReceiveContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, bytes: u32) -> Option<queue::Event> {
+        let context = *self;
+        let lookup = context.lookup;
+        let server = context.server;
+
+        // Datagrams from other hosts are ignored, as are responses that do
+        // not match the query, so that off-path hosts cannot inject answers
+        let from_server = match context.addr.get_addr() {
+            Some(addr) => addr.ip() == server.ip() && addr.port() == server.port(),
+            None => false,
+        };
+        let response = match parse_response(&context.buffer[..bytes as usize]) {
+            Ok(ref response) if !from_server || !lookup.is_response(response) => None,
+            Ok(response) => Some(response),
+            Err(..) if !from_server => None,
+            Err(error) => return Lookup::retry(&lookup, error),
+        };
+        let response = match response {
+            Some(response) => response,
+            None => {
+                return match Lookup::receive_udp(&lookup, context.socket, server, context.expires) {
+                    Ok(()) => None,
+                    Err(error) => Lookup::retry(&lookup, error),
+                };
+            },
+        };
+
+        if response.truncated {
+            return match Lookup::send_tcp(&lookup, server) {
+                Ok(()) => None,
+                Err(error) => Lookup::retry(&lookup, error),
+            };
+        }
+
+        Lookup::complete(&lookup, response)
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        Lookup::retry(&self.lookup, error)
+    }
+}
+
+
+/****************************************************************************
+*
+*   TcpConnectContext
+*
+***/
+
+struct TcpConnectContext {
+    lookup: Arc<Lookup>,
+    socket: Arc<Socket>,
+    buffer: Box<[u8]>,
+}
+
+impl queue::Context for TcpConnectContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, bytes: u32) -> Option<queue::Event> {
+        if bytes as usize != self.buffer.len() {
+            let error = Error::new(ErrorKind::InvalidData, "DNS query not fully sent");
+            return Lookup::retry(&self.lookup, error);
+        }
+
+        match Lookup::receive_tcp(&self.lookup, self.socket, Vec::new()) {
+            Ok(()) => None,
+            Err(error) => Lookup::retry(&self.lookup, error),
+        }
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        Lookup::retry(&self.lookup, error)
+    }
+}
+
+
+/****************************************************************************
+*
+*   TcpReceiveContext
+*
+***/
+
+struct TcpReceiveContext {
+    lookup: Arc<Lookup>,
+    socket: Arc<Socket>,
+    buffer: Box<[u8]>,
+    received: Vec<u8>,
+}
+
+impl queue::Context for TcpReceiveContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, bytes: u32) -> Option<queue::Event> {
+        let context = *self;
+        let lookup = context.lookup;
+
+        if bytes == 0 {
+            let error = Error::new(ErrorKind::InvalidData, "DNS connection closed");
+            return Lookup::retry(&lookup, error);
+        }
+
+        let mut received = context.received;
+        received.extend_from_slice(&context.buffer[..bytes as usize]);
+
+        // Wait for the length prefix and the whole message
+        let expected = if received.len() >= 2 {
+            2 + ((received[0] as usize) << 8 | received[1] as usize)
+        }
+        else {
+            usize::max_value()
+        };
+        if received.len() < expected {
+            return match Lookup::receive_tcp(&lookup, context.socket, received) {
+                Ok(()) => None,
+                Err(error) => Lookup::retry(&lookup, error),
+            };
+        }
+
+        match parse_response(&received[2..expected]) {
+            Ok(ref response) if !lookup.is_response(response) => {
+                let error = Error::new(ErrorKind::InvalidData, "DNS response does not match query");
+                Lookup::retry(&lookup, error)
+            },
+            Ok(response) => Lookup::complete(&lookup, response),
+            Err(error) => Lookup::retry(&lookup, error),
+        }
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        Lookup::retry(&self.lookup, error)
+    }
+}
+
+
+/****************************************************************************
+*
+*   Response
+*
+***/
+
+#[derive(Debug)]
+struct Response {
+    id: u16,
+    truncated: bool,
+    rcode: u8,
+    questions: Vec<(String, u16, u16)>,
+    records: Vec<Record>,
+}
+
+
+/****************************************************************************
+*
+*   Reader
+*
+***/
+
+struct Reader<'a> {
+    message: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    //=======================================================================
+    fn new (message: &'a [u8]) -> Reader<'a> {
+        Reader {
+            message: message,
+            offset: 0,
+        }
+    }
+
+    //=======================================================================
+    // Fixed size reads do not check bounds; callers check has() first
+    fn has (&self, count: usize) -> bool {
+        let len = self.message.len();
+        self.offset <= len && len - self.offset >= count
+    }
+
+    //=======================================================================
+    fn skip (&mut self, count: usize) {
+        self.offset += count;
+    }
+
+    //=======================================================================
+    fn bytes (&mut self, count: usize) -> &'a [u8] {
+        let bytes = &self.message[self.offset..self.offset + count];
+        self.offset += count;
+        bytes
+    }
+
+    //=======================================================================
+    fn u8 (&mut self) -> u8 {
+        self.bytes(1)[0]
+    }
+
+    //=======================================================================
+    fn u16 (&mut self) -> u16 {
+        let b = self.bytes(2);
+        (b[0] as u16) << 8 | b[1] as u16
+    }
+
+    //=======================================================================
+    fn u32 (&mut self) -> u32 {
+        let b = self.bytes(4);
+        (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32
+    }
+
+    //=======================================================================
+    // Reads a possibly compressed name, leaving the offset after the name
+    // as it appears at the current position
+    fn name (&mut self) -> Result<String, Error> {
+        let mut name = String::new();
+        let mut offset = self.offset;
+        let mut resume = None;
+        let mut pointers = 0;
+
+        loop {
+            if offset >= self.message.len() {
+                return Err(malformed());
+            }
+
+            let len = self.message[offset] as usize;
+            match len & 0xc0 {
+                // Label
+                0x00 => {
+                    if len == 0 {
+                        offset += 1;
+                        break;
+                    }
+                    if offset + 1 + len > self.message.len() {
+                        return Err(malformed());
+                    }
+
+                    let label = &self.message[offset + 1..offset + 1 + len];
+                    if !name.is_empty() {
+                        name.push('.');
+                    }
+                    name.push_str(&String::from_utf8_lossy(label));
+                    if name.len() > MAX_NAME_BYTES {
+                        return Err(malformed());
+                    }
+                    offset += 1 + len;
+                },
+
+                // Pointer
+                0xc0 => {
+                    if offset + 2 > self.message.len() || pointers == MAX_POINTERS {
+                        return Err(malformed());
+                    }
+                    if resume.is_none() {
+                        resume = Some(offset + 2);
+                    }
+                    pointers += 1;
+                    offset = (len & 0x3f) << 8 | self.message[offset + 1] as usize;
+                },
+
+                _ => return Err(malformed()),
+            }
+        }
+
+        self.offset = resume.unwrap_or(offset);
+        Ok(name)
+    }
+}
+
+
+/****************************************************************************
+*
+*   Private functions
+*
+***/
+
+//===========================================================================
+// Windows lists the deprecated site-local fec0:0:0:ffff::1-3 servers on
+// adapters without IPv6 DNS configured
+fn is_placeholder (addr: &SocketAddr) -> bool {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.segments() {
+            [0xfec0, 0, 0, 0xffff, 0, 0, 0, n] => n >= 1 && n <= 3,
+            _ => false,
+        },
+        IpAddr::V4(..) => false,
+    }
+}
+
+//===========================================================================
+fn malformed () -> Error {
+    Error::new(ErrorKind::InvalidData, "Malformed DNS message")
+}
+
+//===========================================================================
+// Creates a socket bound to the wildcard address of the server's family and
+// associated with the queue
+fn open (socket: Result<Socket, Error>, server: SocketAddr, queue: &queue::Queue)
+    -> Result<Socket, Error>
+{
+    let socket = match socket {
+        Ok(socket) => socket,
+        Err(error) => return Err(error),
+    };
+
+    if let Err(error) = socket.bind(SocketAddr::new_unspecified(server.family())) {
+        return Err(error);
+    }
+
+    match queue::associate(queue, socket.handle()) {
+        Ok(..) => Ok(socket),
+        Err(error) => Err(error),
+    }
+}
+
+//===========================================================================
+fn encode_query (id: u16, name: &str, record_type: RecordType) -> Result<Vec<u8>, Error> {
+    let mut message = Vec::with_capacity(HEADER_BYTES + name.len() + 6);
+
+    // Header: one question, recursion desired
+    let header = [id, FLAG_RD, 1, 0, 0, 0];
+    for field in header.iter() {
+        message.push((field >> 8) as u8);
+        message.push(*field as u8);
+    }
+
+    // Name
+    let name = name.trim_right_matches('.');
+    let start = message.len();
+    if !name.is_empty() {
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > MAX_LABEL_BYTES {
+                return Err(Error::new(ErrorKind::InvalidInput, "Invalid DNS name"));
+            }
+            message.push(label.len() as u8);
+            message.extend_from_slice(label.as_bytes());
+        }
+    }
+    message.push(0);
+    if message.len() - start > MAX_NAME_BYTES {
+        return Err(Error::new(ErrorKind::InvalidInput, "Invalid DNS name"));
+    }
+
+    // Type and class
+    for field in [record_type.code(), CLASS_IN].iter() {
+        message.push((field >> 8) as u8);
+        message.push(*field as u8);
+    }
+
+    Ok(message)
+}
+
+//===========================================================================
+fn parse_response (message: &[u8]) -> Result<Response, Error> {
+    let mut reader = Reader::new(message);
+
+    // Header
+    if !reader.has(HEADER_BYTES) {
+        return Err(malformed());
+    }
+    let id = reader.u16();
+    let flags = reader.u16();
+    let questions = reader.u16();
+    let answers = reader.u16();
+    reader.skip(4);
+
+    if flags & FLAG_QR == 0 {
+        return Err(malformed());
+    }
+
+    // Questions, which are checked against the query
+    let mut question_list = Vec::with_capacity(questions as usize);
+    for _ in 0..questions {
+        let name = match reader.name() {
+            Ok(name) => name,
+            Err(error) => return Err(error),
+        };
+        if !reader.has(4) {
+            return Err(malformed());
+        }
+        let code = reader.u16();
+        let class = reader.u16();
+        question_list.push((name, code, class));
+    }
+
+    // Answers of types that are not understood are skipped
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        let name = match reader.name() {
+            Ok(name) => name,
+            Err(error) => return Err(error),
+        };
+        if !reader.has(10) {
+            return Err(malformed());
+        }
+        let code = reader.u16();
+        let class = reader.u16();
+        let ttl = reader.u32();
+        let len = reader.u16() as usize;
+        if !reader.has(len) {
+            return Err(malformed());
+        }
+
+        let end = reader.offset + len;
+        let data = if class != CLASS_IN {
+            None
+        }
+        else if code == RecordType::A.code() && len == 4 {
+            let b = reader.bytes(4);
+            Some(RecordData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3])))
+        }
+        else if code == RecordType::AAAA.code() && len == 16 {
+            let mut s = [0u16; 8];
+            for segment in s.iter_mut() {
+                *segment = reader.u16();
+            }
+            Some(RecordData::AAAA(Ipv6Addr::new(s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7])))
+        }
+        else if code == RecordType::SRV.code() && len > 6 {
+            let priority = reader.u16();
+            let weight = reader.u16();
+            let port = reader.u16();
+            let target = match reader.name() {
+                Ok(target) => target,
+                Err(error) => return Err(error),
+            };
+            Some(RecordData::SRV {
+                priority: priority,
+                weight: weight,
+                port: port,
+                target: target,
+            })
+        }
+        else if code == RecordType::TXT.code() {
+            let mut strings = Vec::new();
+            while reader.offset < end {
+                let len = reader.u8() as usize;
+                if reader.offset + len > end {
+                    return Err(malformed());
+                }
+                strings.push(reader.bytes(len).to_vec());
+            }
+            Some(RecordData::TXT(strings))
+        }
+        else {
+            None
+        };
+
+        // Names inside the data must not run past it
+        if reader.offset > end {
+            return Err(malformed());
+        }
+        reader.offset = end;
+
+        if let Some(data) = data {
+            records.push(Record {
+                name: name,
+                ttl: ttl,
+                data: data,
+            });
+        }
+    }
+
+    Ok(Response {
+        id: id,
+        truncated: flags & FLAG_TC != 0,
+        rcode: (flags & 0x000f) as u8,
+        questions: question_list,
+        records: records,
+    })
+}
+
+
+/****************************************************************************
+*
+*   Tests
+*
+***/
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net as std_net;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use error::ErrorKind;
+    use net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use queue::{Event, Queue};
+    use super::*;
+    use super::{encode_query, is_placeholder, parse_response, Cache};
+
+    //=======================================================================
+    // Builds a response to query carrying the given raw answer records
+    fn respond (query: &[u8], flags: u16, answers: &[Vec<u8>]) -> Vec<u8> {
+        let mut message = query.to_vec();
+        message[2] = (flags >> 8) as u8;
+        message[3] = flags as u8;
+        message[6] = 0;
+        message[7] = answers.len() as u8;
+        for answer in answers {
+            message.extend_from_slice(answer);
+        }
+        message
+    }
+
+    //=======================================================================
+    // Answer record whose name points at the question name
+    fn answer (code: u16, ttl: u32, data: &[u8]) -> Vec<u8> {
+        let mut record = vec![0xc0, 12, (code >> 8) as u8, code as u8, 0, 1];
+        record.extend_from_slice(&[(ttl >> 24) as u8, (ttl >> 16) as u8, (ttl >> 8) as u8, ttl as u8]);
+        record.extend_from_slice(&[(data.len() >> 8) as u8, data.len() as u8]);
+        record.extend_from_slice(data);
+        record
+    }
+
+    //=======================================================================
+    fn answer_a (ttl: u32, ip: [u8; 4]) -> Vec<u8> {
+        answer(RecordType::A.code(), ttl, &ip)
+    }
+
+    //=======================================================================
+    fn loopback () -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0)
+    }
+
+    //=======================================================================
+    fn dequeue_answer (queue: &Queue) -> (Question, Result<Vec<Record>, ::error::Error>) {
+        match queue.dequeue().unwrap() {
+            Event::DnsAnswer(question, result) => (question, result),
+            event => panic!("Expected Event::DnsAnswer, got {:?}", event),
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn encode () {
+        let message = encode_query(0x1234, "www.example.com.", RecordType::AAAA).unwrap();
+        assert_eq!(&message[..12], &[0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&message[12..], &b"\x03www\x07example\x03com\x00\x00\x1c\x00\x01"[..]);
+
+        let message = encode_query(1, "", RecordType::A).unwrap();
+        assert_eq!(&message[12..], &[0, 0, 1, 0, 1]);
+    }
+
+    //=======================================================================
+    #[test]
+    fn encode_invalid () {
+        let long_label: String = ::std::iter::repeat('a').take(64).collect();
+        let long_name: String = ::std::iter::repeat("abcdefg.").take(40).collect();
+
+        for name in ["a..b", ".a", &long_label[..], &long_name[..]].iter() {
+            let error = encode_query(1, name, RecordType::A).err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn parse_records () {
+        let query = encode_query(7, "example.com", RecordType::A).unwrap();
+
+        let srv = [0, 10, 0, 20, 0x1f, 0x90, 4, b'h', b'o', b's', b't', 0xc0, 12];
+        let txt = b"\x05hello\x00\x03abc";
+        let aaaa = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        let cname = [0xc0, 12];
+        let response = respond(&query, 0x8180, &[
+            answer_a(300, [10, 0, 0, 1]),
+            answer(RecordType::AAAA.code(), 60, &aaaa),
+            answer(RecordType::SRV.code(), 10, &srv),
+            answer(RecordType::TXT.code(), 5, &txt[..]),
+            answer(5, 5, &cname),
+        ]);
+
+        let response = parse_response(&response).unwrap();
+        assert_eq!(response.id, 7);
+        assert!(!response.truncated);
+        assert_eq!(response.rcode, 0);
+        assert_eq!(response.records, vec![
+            Record {
+                name: "example.com".to_string(),
+                ttl: 300,
+                data: RecordData::A(Ipv4Addr::new(10, 0, 0, 1)),
+            },
+            Record {
+                name: "example.com".to_string(),
+                ttl: 60,
+                data: RecordData::AAAA(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            },
+            Record {
+                name: "example.com".to_string(),
+                ttl: 10,
+                data: RecordData::SRV {
+                    priority: 10,
+                    weight: 20,
+                    port: 8080,
+                    target: "host.example.com".to_string(),
+                },
+            },
+            Record {
+                name: "example.com".to_string(),
+                ttl: 5,
+                data: RecordData::TXT(vec![b"hello".to_vec(), Vec::new(), b"abc".to_vec()]),
+            },
+        ]);
+    }
+
+    //=======================================================================
+    #[test]
+    fn parse_flags () {
+        let query = encode_query(9, "example.com", RecordType::A).unwrap();
+
+        let response = parse_response(&respond(&query, 0x8183, &[])).unwrap();
+        assert!(!response.truncated);
+        assert_eq!(response.rcode, 3);
+
+        let response = parse_response(&respond(&query, 0x8380, &[])).unwrap();
+        assert!(response.truncated);
+
+        // Queries are not responses
+        assert!(parse_response(&query).is_err());
+    }
+
+    //=======================================================================
+    #[test]
+    fn parse_malformed () {
+        let query = encode_query(9, "example.com", RecordType::A).unwrap();
+        let response = respond(&query, 0x8180, &[answer_a(1, [1, 2, 3, 4])]);
+
+        // Every truncation of a valid response must fail cleanly
+        for len in 0..response.len() {
+            assert!(parse_response(&response[..len]).is_err(), "len {}", len);
+        }
+
+        // Pointer loop
+        let mut looped = respond(&query, 0x8180, &[answer_a(1, [1, 2, 3, 4])]);
+        let offset = query.len();
+        looped[offset] = 0xc0;
+        looped[offset + 1] = offset as u8;
+        let error = parse_response(&looped).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    //=======================================================================
+    #[test]
+    fn reader_past_end () {
+        let message = [0u8; 4];
+        let mut reader = Reader::new(&message);
+        reader.skip(6);
+        assert!(!reader.has(0));
+        assert!(!reader.has(1));
+    }
+
+    //=======================================================================
+    #[test]
+    fn parse_config () {
+        let config = Config::parse("\
+            # comment\n\
+            domain example.com\n\
+            nameserver 10.0.0.53\n\
+            nameserver fe80::1%2 ; link local\n\
+            nameserver not-an-address\n\
+            options ndots:2 timeout:3 attempts:4\n\
+        ");
+
+        assert_eq!(config.servers.len(), 2);
+        assert_eq!(config.servers[0], SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 53)), 53));
+        assert_eq!(config.servers[1].port(), 53);
+        assert_eq!(config.timeout, Duration::from_secs(3));
+        assert_eq!(config.attempts, 4);
+
+        let config = Config::parse("");
+        assert!(config.servers.is_empty());
+        assert_eq!(config.timeout, Duration::from_secs(5));
+        assert_eq!(config.attempts, 2);
+    }
+
+    //=======================================================================
+    #[test]
+    fn system_config () {
+        let config = Config::system().unwrap();
+        for server in config.servers.iter() {
+            assert_eq!(server.port(), 53);
+            assert!(!is_placeholder(server));
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn cache_ttl () {
+        let mut cache = Cache::new();
+        let now = Instant::now();
+        let question = Question {
+            name: "example.com".to_string(),
+            record_type: RecordType::A,
+        };
+        let record = Record {
+            name: "example.com".to_string(),
+            ttl: 30,
+            data: RecordData::A(Ipv4Addr::new(1, 2, 3, 4)),
+        };
+
+        cache.insert(question.clone(), &[record.clone()], now);
+
+        let records = cache.get(&question, now + Duration::from_secs(10)).unwrap();
+        assert_eq!(records[0].ttl, 20);
+        assert!(cache.get(&question, now + Duration::from_secs(30)).is_none());
+
+        // Zero TTLs are not cached
+        let mut record = record;
+        record.ttl = 0;
+        cache.insert(question.clone(), &[record], now);
+        assert!(cache.get(&question, now).is_none());
+    }
+
+    //=======================================================================
+    #[test]
+    fn query_udp () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        // Responder answering a single query
+        let responder = std_net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = responder.local_addr().unwrap().port();
+        let thread = thread::spawn(move || {
+            let mut buffer = [0u8; 512];
+            let (bytes, from) = responder.recv_from(&mut buffer).unwrap();
+            let response = respond(&buffer[..bytes], 0x8180, &[answer_a(60, [10, 1, 2, 3])]);
+            responder.send_to(&response, from).unwrap();
+        });
+
+        let server = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+        let client = Client::new(Config::new(vec![server]), queue.clone());
+
+        client.query("Example.Test.", RecordType::A).unwrap();
+        let (question, result) = dequeue_answer(&queue);
+        assert_eq!(question.name, "example.test");
+        assert_eq!(question.record_type, RecordType::A);
+        let records = result.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].data, RecordData::A(Ipv4Addr::new(10, 1, 2, 3)));
+        thread.join().unwrap();
+
+        // The responder is gone, so this must come from the cache
+        client.query("example.test", RecordType::A).unwrap();
+        let (_, result) = dequeue_answer(&queue);
+        assert_eq!(result.unwrap()[0].data, RecordData::A(Ipv4Addr::new(10, 1, 2, 3)));
+    }
+
+    //=======================================================================
+    #[test]
+    fn query_ignores_forged () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let responder = std_net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let forger = std_net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = responder.local_addr().unwrap().port();
+        let thread = thread::spawn(move || {
+            let mut buffer = [0u8; 512];
+            let (bytes, from) = responder.recv_from(&mut buffer).unwrap();
+            let query = &buffer[..bytes];
+
+            // Right id from the wrong host
+            let forged = respond(query, 0x8180, &[answer_a(60, [6, 6, 6, 6])]);
+            forger.send_to(&forged, from).unwrap();
+
+            // Right host and id for a different question
+            let mut other = query.to_vec();
+            other[13] = b'z';
+            let forged = respond(&other, 0x8180, &[answer_a(60, [7, 7, 7, 7])]);
+            responder.send_to(&forged, from).unwrap();
+
+            let response = respond(query, 0x8180, &[answer_a(60, [10, 0, 0, 1])]);
+            responder.send_to(&response, from).unwrap();
+        });
+
+        let server = SocketAddr::new(loopback().ip(), port);
+        let client = Client::new(Config::new(vec![server]), queue.clone());
+
+        client.query("spoof.test", RecordType::A).unwrap();
+        let (_, result) = dequeue_answer(&queue);
+        let records = result.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].data, RecordData::A(Ipv4Addr::new(10, 0, 0, 1)));
+        thread.join().unwrap();
+    }
+
+    //=======================================================================
+    #[test]
+    fn query_nxdomain () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let responder = std_net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = responder.local_addr().unwrap().port();
+        let thread = thread::spawn(move || {
+            let mut buffer = [0u8; 512];
+            let (bytes, from) = responder.recv_from(&mut buffer).unwrap();
+            responder.send_to(&respond(&buffer[..bytes], 0x8183, &[]), from).unwrap();
+        });
+
+        let server = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+        let client = Client::new(Config::new(vec![server]), queue.clone());
+
+        client.query("missing.test", RecordType::TXT).unwrap();
+        let (_, result) = dequeue_answer(&queue);
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::NotFound);
+        thread.join().unwrap();
+    }
+
+    //=======================================================================
+    #[test]
+    fn query_timeout () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        // Responder that never answers
+        let responder = std_net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = responder.local_addr().unwrap().port();
+
+        let server = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+        let mut config = Config::new(vec![server]);
+        config.timeout = Duration::from_millis(50);
+        let client = Client::new(config, queue.clone());
+
+        client.query("slow.test", RecordType::A).unwrap();
+        let (_, result) = dequeue_answer(&queue);
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::TimedOut);
+        drop(responder);
+    }
+
+    //=======================================================================
+    #[test]
+    fn query_tcp_fallback () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        // UDP responder that always truncates, and a TCP responder on the
+        // same port that answers in full
+        let udp = std_net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = udp.local_addr().unwrap().port();
+        let tcp = std_net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+
+        let udp_thread = thread::spawn(move || {
+            let mut buffer = [0u8; 512];
+            let (bytes, from) = udp.recv_from(&mut buffer).unwrap();
+            udp.send_to(&respond(&buffer[..bytes], 0x8380, &[]), from).unwrap();
+        });
+        let tcp_thread = thread::spawn(move || {
+            let (mut stream, _) = tcp.accept().unwrap();
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut query = vec![0u8; (len[0] as usize) << 8 | len[1] as usize];
+            stream.read_exact(&mut query).unwrap();
+
+            let answers: Vec<Vec<u8>> = (0..100).map(|i| answer_a(60, [10, 0, 0, i])).collect();
+            let response = respond(&query, 0x8180, &answers);
+            let len = [(response.len() >> 8) as u8, response.len() as u8];
+            stream.write_all(&len).unwrap();
+
+            // Send in pieces to exercise reassembly
+            for chunk in response.chunks(100) {
+                stream.write_all(chunk).unwrap();
+                stream.flush().unwrap();
+            }
+        });
+
+        let server = SocketAddr::new(loopback().ip(), port);
+        let client = Client::new(Config::new(vec![server]), queue.clone());
+
+        client.query("big.test", RecordType::A).unwrap();
+        let (_, result) = dequeue_answer(&queue);
+        let records = result.unwrap();
+        assert_eq!(records.len(), 100);
+        assert_eq!(records[99].data, RecordData::A(Ipv4Addr::new(10, 0, 0, 99)));
+
+        udp_thread.join().unwrap();
+        tcp_thread.join().unwrap();
+    }
+}
@@ -8,9 +8,15 @@
 ***/
 
 mod addr;
+mod cell;
 mod init;
+mod resolve;
 mod socket;
 mod tcp;
+mod udp;
+mod unix;
+
+pub mod dns;
 
 // Initialization exports
 pub use self::init::*;
@@ -22,6 +28,18 @@ pub use std::net::Ipv6Addr;
 pub use std::net::SocketAddrV4;
 pub use std::net::SocketAddrV6;
 
+// Resolution exports
+pub use self::resolve::resolve;
+
 // TCP exports
 pub use self::tcp::TcpListener;
-pub use self::tcp::TcpStream;
\ No newline at end of file
+pub use self::tcp::TcpStream;
+
+// UDP exports
+pub use self::udp::UdpSocket;
+
+// Unix socket exports
+pub use self::unix::UnixAddr;
+pub use self::unix::UnixListener;
+pub use self::unix::UnixStream;
+pub use self::unix::UCred;
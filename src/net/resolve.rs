@@ -0,0 +1,315 @@
+/****************************************************************************
+*
+*   net/resolve.rs
+*   ioq
+*
+*   Copyright 2015 Tyler Cole
+*
+***/
+
+use std::io::{self, Write};
+use std::ptr;
+
+use sys;
+use queue;
+use error::Error;
+
+use super::addr::SocketAddr;
+
+
+/****************************************************************************
+*
+*   Request
+*
+***/
+
+// Outstanding GetAddrInfoExW call. The OVERLAPPED must be first so that the
+// completion routine can recover the request from it.
+#[repr(C)]
+struct Request {
+    overlapped: sys::OVERLAPPED,
+    results: *mut sys::ADDRINFOEXW,
+    host: String,
+    port: u16,
+    queue: queue::Queue,
+}
+
+impl Request {
+    //=======================================================================
+    fn complete (self: Box<Self>, code: i32) -> Result<(), Error> {
+        let request = *self;
+
+        let result = if code == 0 {
+            Ok(addrs(request.results))
+        }
+        else {
+            Err(Error::from_os_error_code(code))
+        };
+        if !request.results.is_null() {
+            unsafe { sys::FreeAddrInfoExW(request.results) };
+        }
+
+        let context = Box::new(ResolveContext {
+            host: request.host,
+            port: request.port,
+            result: result,
+        });
+        queue::post(&request.queue, context)
+    }
+}
+
+
+/****************************************************************************
+*
+*   ResolveContext
+*
+***/
+
+struct ResolveContext {
+    host: String,
+    port: u16,
+    result: Result<Vec<SocketAddr>, Error>,
+}
+
+impl queue::Context for ResolveContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, _: u32) -> Option<queue::Event> {
+        let context = *self;
+        Some(queue::Event::Resolved(context.host, context.port, context.result))
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        let context = *self;
+        Some(queue::Event::Resolved(context.host, context.port, Err(error)))
+    }
+}
+
+
+/****************************************************************************
+*
+*   Public functions
+*
+***/
+
+//===========================================================================
+// Resolves host with an asynchronous GetAddrInfoExW call and completes with
+// Event::Resolved, which carries host and port so that concurrent
+// resolutions can be told apart. The system resolver calls back on its own
+// threads, so no thread is created per call.
+pub fn resolve (host: &str, port: u16, queue: queue::Queue) -> Result<(), Error> {
+    let name = wide(host);
+    let service = wide(&port.to_string());
+
+    let mut hints = sys::ADDRINFOEXW::new();
+    hints.ai_family = sys::AF_UNSPEC;
+    hints.ai_socktype = sys::SOCK_STREAM;
+
+    let request = Box::into_raw(Box::new(Request {
+        overlapped: sys::OVERLAPPED::new(),
+        results: ptr::null_mut(),
+        host: host.to_string(),
+        port: port,
+        queue: queue,
+    }));
+
+    let code = unsafe {
+        sys::GetAddrInfoExW(
+            name.as_ptr(),
+            service.as_ptr(),
+            sys::NS_ALL,
+            ptr::null_mut(),
+            &hints,
+            &mut (*request).results,
+            ptr::null_mut(),
+            &mut (*request).overlapped,
+            Some(resolve_complete),
+            ptr::null_mut()
+        )
+    };
+
+    // Anything other than a pending call has completed synchronously and
+    // will not invoke the completion routine
+    match code {
+        sys::ERROR_IO_PENDING => Ok(()),
+        0 => unsafe { Box::from_raw(request) }.complete(code),
+        code => {
+            drop(unsafe { Box::from_raw(request) });
+            Err(Error::from_os_error_code(code))
+        },
+    }
+}
+
+
+/****************************************************************************
+*
+*   Private functions
+*
+***/
+
+//===========================================================================
+extern "stdcall" fn resolve_complete (
+    error: sys::DWORD,
+    _: sys::DWORD,
+    overlapped: sys::LPOVERLAPPED
+) {
+    let request = unsafe { Box::from_raw(overlapped as *mut Request) };
+
+    // Running on a system thread, there is no caller to return the error
+    // to, and the waiting caller will never see Event::Resolved
+    if let Err(error) = request.complete(error as i32) {
+        let _ = writeln!(io::stderr(), "ioq: lost Event::Resolved: {:?}", error);
+    }
+}
+
+//===========================================================================
+fn addrs (mut info: *const sys::ADDRINFOEXW) -> Vec<SocketAddr> {
+    let mut addrs = Vec::new();
+    while !info.is_null() {
+        let entry = unsafe { &*info };
+        let storage = sys::sockaddr_storage::from_raw(entry.ai_addr, entry.ai_addrlen as i32);
+        if let Some(addr) = storage.get_addr() {
+            addrs.push(addr);
+        }
+        info = entry.ai_next;
+    }
+
+    addrs
+}
+
+//===========================================================================
+fn wide (s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(Some(0)).collect()
+}
+
+
+/****************************************************************************
+*
+*   Tests
+*
+***/
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::path::PathBuf;
+
+    use net::{self, IpAddr, SocketAddr};
+    use queue::{Event, Queue};
+    use super::*;
+
+    //=======================================================================
+    fn resolved (queue: &Queue) -> Vec<SocketAddr> {
+        match queue.dequeue().unwrap() {
+            Event::Resolved(_, _, result) => result.unwrap(),
+            event => panic!("Expected Event::Resolved, got {:?}", event),
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn resolve_localhost () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        resolve("localhost", 8080, queue.clone()).unwrap();
+
+        let addrs = resolved(&queue);
+        assert!(!addrs.is_empty());
+        for addr in addrs {
+            assert_eq!(addr.port(), 8080);
+            match addr.ip() {
+                IpAddr::V4(ip) => assert_eq!(ip.octets()[0], 127),
+                IpAddr::V6(ip) => assert_eq!(ip, Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+            }
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn resolve_literal () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        resolve("10.20.30.40", 53, queue.clone()).unwrap();
+
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 20, 30, 40));
+        assert_eq!(resolved(&queue), vec![SocketAddr::new(ip, 53)]);
+    }
+
+    //=======================================================================
+    #[test]
+    fn resolve_invalid () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        resolve("invalid host name", 80, queue.clone()).unwrap();
+
+        match queue.dequeue().unwrap() {
+            Event::Resolved(host, _, result) => {
+                assert_eq!(host, "invalid host name");
+                assert!(result.is_err());
+            },
+            event => panic!("Expected Event::Resolved, got {:?}", event),
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn resolve_concurrent () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let requests = [("127.0.0.1", 1), ("::1", 2), ("localhost", 3)];
+        for &(host, port) in requests.iter() {
+            resolve(host, port, queue.clone()).unwrap();
+        }
+
+        // Each result is matched to its request whatever order they finish
+        let mut remaining: Vec<(&str, u16)> = requests.to_vec();
+        while !remaining.is_empty() {
+            match queue.dequeue().unwrap() {
+                Event::Resolved(host, port, result) => {
+                    let index = remaining.iter()
+                        .position(|&(h, p)| h == host && p == port)
+                        .unwrap();
+                    remaining.remove(index);
+
+                    for addr in result.unwrap() {
+                        assert_eq!(addr.port(), port);
+                        assert!(addr.ip().is_loopback());
+                    }
+                },
+                event => panic!("Expected Event::Resolved, got {:?}", event),
+            }
+        }
+    }
+    //=======================================================================
+    #[test]
+    fn resolve_hosts_file () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let root = env::var("SystemRoot").unwrap_or("C:\\Windows".to_string());
+        let path: PathBuf = [&root, "System32", "drivers", "etc", "hosts"].iter().collect();
+        let text = fs::read_to_string(path).unwrap();
+
+        // Every name listed resolves to its entry's address. The default
+        // hosts file has only comments, leaving nothing to check.
+        for line in text.lines() {
+            let mut fields = line.split('#').next().unwrap().split_whitespace();
+            let ip: IpAddr = match fields.next().map(|field| field.parse()) {
+                Some(Ok(ip)) => ip,
+                _ => continue,
+            };
+
+            for host in fields {
+                resolve(host, 80, queue.clone()).unwrap();
+                let addrs = resolved(&queue);
+                assert!(addrs.contains(&SocketAddr::new(ip, 80)), "{} -> {:?}", host, addrs);
+            }
+        }
+    }
+}
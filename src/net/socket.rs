@@ -8,43 +8,16 @@
 ***/
 
 use std::mem;
+use std::ptr;
+use std::sync::atomic::ATOMIC_USIZE_INIT;
 
 use sys;
+use queue;
 use error::Error;
-use handle::Handle;
+use handle::{HandleRef, OwnedSocket};
 use super::addr::{SocketAddr, AddrFamily};
 
 
-/****************************************************************************
-*
-*   HandleExt
-*
-***/
-
-trait HandleExt {
-    fn invalid_socket () -> Handle;
-    fn from_socket (socket: sys::SOCKET) -> Handle;
-    fn to_socket (&self) -> sys::SOCKET;
-}
-
-impl HandleExt for Handle {
-    //=======================================================================
-    fn invalid_socket () -> Handle {
-        Handle::from_socket(sys::INVALID_SOCKET)
-    }
-
-    //=======================================================================
-    fn from_socket (socket: sys::SOCKET) -> Handle {
-        Handle::from_raw(socket as sys::HANDLE)
-    }
-
-    //=======================================================================
-    fn to_socket (&self) -> sys::SOCKET {
-        self.to_raw() as sys::SOCKET
-    }
-}
-
-
 /****************************************************************************
 *
 *   Socket
@@ -53,17 +26,12 @@ impl HandleExt for Handle {
 
 #[derive(Debug)]
 pub struct Socket {
-    handle: Handle,
+    socket: OwnedSocket,
 }
 
 impl Socket {
-    pub fn handle (&self) -> Handle { self.handle }
-    pub fn to_raw (&self) -> sys::SOCKET { self.handle.to_socket() }
-
-    //=======================================================================
-    pub fn is_valid (&self) -> bool {
-        self.handle.to_socket() != sys::INVALID_SOCKET
-    }
+    pub fn handle (&self) -> HandleRef<'_> { self.socket.handle() }
+    pub fn to_raw (&self) -> sys::SOCKET { self.socket.to_raw() }
 
     //=======================================================================
     pub fn new_from_family (family: AddrFamily) -> Result<Socket, Error> {
@@ -91,6 +59,115 @@ impl Socket {
         )
     }
 
+    //=======================================================================
+    pub fn new_udp (family: AddrFamily) -> Result<Socket, Error> {
+        let af = match family {
+            AddrFamily::V4 => sys::AF_INET,
+            AddrFamily::V6 => sys::AF_INET6,
+        };
+
+        let socket = match Socket::new(af, sys::SOCK_DGRAM, sys::IPPROTO_UDP) {
+            Ok(socket) => socket,
+            Err(error) => return Err(error),
+        };
+
+        // Otherwise an ICMP port unreachable for an earlier send fails the
+        // next receive with WSAECONNRESET
+        let enabled: sys::BOOL = 0;
+        let mut bytes: sys::DWORD = 0;
+        let success = unsafe {
+            sys::WSAIoctl(
+                socket.to_raw(),
+                sys::SIO_UDP_CONNRESET,
+                &enabled as *const _ as sys::LPVOID,
+                mem::size_of_val(&enabled) as sys::DWORD,
+                ptr::null_mut(),
+                0,
+                &mut bytes,
+                ptr::null_mut(),
+                None
+            ) == 0
+        };
+
+        if !success {
+            return Err(Socket::last_error());
+        }
+
+        Ok(socket)
+    }
+
+    //=======================================================================
+    pub fn new_unix (t: i32) -> Result<Socket, Error> {
+        Socket::new(sys::AF_UNIX, t, 0)
+    }
+
+    //=======================================================================
+    // Takes ownership of an open socket that nothing else will close
+    pub unsafe fn from_raw (raw: sys::SOCKET) -> Socket {
+        Socket {
+            socket: OwnedSocket::from_raw(raw)
+        }
+    }
+
+    //=======================================================================
+    // Releases ownership without closing the socket
+    pub fn into_raw (self) -> sys::SOCKET {
+        self.socket.into_raw()
+    }
+
+    //=======================================================================
+    // Opens a socket described by WSADuplicateSocket in another process
+    pub fn from_protocol_info (info: &sys::WSAPROTOCOL_INFOW) -> Result<Socket, Error> {
+        let raw = unsafe {
+            sys::WSASocketW(
+                sys::FROM_PROTOCOL_INFO,
+                sys::FROM_PROTOCOL_INFO,
+                sys::FROM_PROTOCOL_INFO,
+                info,
+                0,
+                sys::WSA_FLAG_OVERLAPPED
+            )
+        };
+
+        if raw == sys::INVALID_SOCKET {
+            Err(Socket::last_error())
+        }
+        else {
+            Ok(unsafe { Socket::from_raw(raw) })
+        }
+    }
+
+    //=======================================================================
+    // Removes any completion port association, which a duplicated socket
+    // shares with the socket it was duplicated from, so that it can be
+    // associated with another queue. Completions for operations still
+    // outstanding on the original are no longer queued.
+    pub fn detach_completion_port (&self) -> Result<(), Error> {
+        let mut status = sys::IO_STATUS_BLOCK::new();
+        let mut info = sys::FILE_COMPLETION_INFORMATION {
+            Port: sys::NULL_HANDLE,
+            Key: ptr::null_mut(),
+        };
+
+        let code = unsafe {
+            sys::NtSetInformationFile(
+                self.to_raw() as sys::HANDLE,
+                &mut status,
+                &mut info as *mut _ as sys::PVOID,
+                mem::size_of::<sys::FILE_COMPLETION_INFORMATION>() as u32,
+                sys::FILE_REPLACE_COMPLETION_INFORMATION
+            )
+        };
+
+        if code >= 0 {
+            Ok(())
+        }
+        else {
+            let code = unsafe { sys::RtlNtStatusToDosError(code) };
+            Err(Error::from_os_error_code(code as i32))
+        }
+    }
+
     //=======================================================================
     pub fn new (af: i32, t: i32, p: i32) -> Result<Socket, Error> {
         let raw = unsafe {
@@ -101,9 +178,7 @@ impl Socket {
             Err(Socket::last_error())
         }
         else {
-            Ok(Socket {
-                handle: Handle::from_raw(raw as sys::HANDLE)
-            })
+            Ok(unsafe { Socket::from_raw(raw) })
         }
     }
 
@@ -111,12 +186,42 @@ impl Socket {
     pub fn bind (&self, addr: SocketAddr) -> Result<(), Error> {
         let mut storage = [0u8; sys::SOCKADDR_MAX_BYTES];
         let (sockaddr, len) = Socket::sockaddr_from_addr(addr, &mut storage);
+        self.bind_raw(sockaddr, len)
+    }
 
+    //=======================================================================
+    pub fn bind_raw (&self, sockaddr: sys::PVOID, len: i32) -> Result<(), Error> {
         let success = unsafe {
             sys::bind(
-                self.handle.to_socket(),
+                self.to_raw(),
                 sockaddr,
-                len as i32
+                len
+            ) == 0
+        };
+
+        if success {
+            Ok(())
+        }
+        else {
+            Err(Socket::last_error())
+        }
+    }
+
+    //=======================================================================
+    // Synchronous connect, only suitable for connectionless sockets
+    pub fn connect (&self, addr: SocketAddr) -> Result<(), Error> {
+        let mut storage = [0u8; sys::SOCKADDR_MAX_BYTES];
+        let (sockaddr, len) = Socket::sockaddr_from_addr(addr, &mut storage);
+        self.connect_raw(sockaddr, len)
+    }
+
+    //=======================================================================
+    pub fn connect_raw (&self, sockaddr: sys::PVOID, len: i32) -> Result<(), Error> {
+        let success = unsafe {
+            sys::connect(
+                self.to_raw(),
+                sockaddr,
+                len
             ) == 0
         };
 
@@ -132,7 +237,7 @@ impl Socket {
     pub fn listen (&self) -> Result<(), Error> {
         let success = unsafe {
             sys::listen(
-                self.handle.to_socket(),
+                self.to_raw(),
                 sys::SOMAXCONN
             )
         } == 0;
@@ -145,9 +250,58 @@ impl Socket {
         }
     }
 
+    //=======================================================================
+    // Synchronous accept; blocks until a connection arrives
+    pub fn accept (&self) -> Result<Socket, Error> {
+        let raw = unsafe {
+            sys::accept(
+                self.to_raw(),
+                ptr::null_mut(),
+                ptr::null_mut()
+            )
+        };
+
+        if raw == sys::INVALID_SOCKET {
+            Err(Socket::last_error())
+        }
+        else {
+            Ok(unsafe { Socket::from_raw(raw) })
+        }
+    }
+
+    //=======================================================================
+    pub fn set_option<T> (&self, level: i32, name: i32, value: &T)
+        -> Result<(), Error>
+    {
+        let success = unsafe {
+            sys::setsockopt(
+                self.to_raw(),
+                level,
+                name,
+                value as *const T as sys::LPCVOID,
+                mem::size_of::<T>() as i32
+            ) == 0
+        };
+
+        if success {
+            Ok(())
+        }
+        else {
+            Err(Socket::last_error())
+        }
+    }
+
     //=======================================================================
     pub fn get_addr (&self) -> Result<SocketAddr, Error> {
-        // Get local address
+        match self.get_raw_addr() {
+            Ok((storage, _)) => Ok(storage.get_addr().unwrap()),
+            Err(error) => Err(error),
+        }
+    }
+
+    //=======================================================================
+    // Local address and its length, for families get_addr does not handle
+    pub fn get_raw_addr (&self) -> Result<(sys::sockaddr_storage, i32), Error> {
         let mut storage = sys::sockaddr_storage::new();
         let mut bytes = mem::size_of_val(&storage) as i32;
         let success = unsafe {
@@ -158,9 +312,8 @@ impl Socket {
             ) == 0
         };
 
-        // Result
         if success {
-            Ok(storage.get_addr().unwrap())
+            Ok((storage, bytes))
         }
         else {
             Err(Socket::last_error())
@@ -168,11 +321,261 @@ impl Socket {
     }
 
     //=======================================================================
-    pub fn close (&mut self) {
-        if self.is_valid() {
-            unsafe { sys::closesocket(self.handle.to_socket()) };
-            self.handle = Handle::invalid_socket();
+    pub fn get_peer_addr (&self) -> Result<SocketAddr, Error> {
+        let mut storage = sys::sockaddr_storage::new();
+        let mut bytes = mem::size_of_val(&storage) as i32;
+        let success = unsafe {
+            sys::getpeername(
+                self.to_raw(),
+                &mut storage as *mut _ as sys::PVOID,
+                &mut bytes
+            ) == 0
+        };
+
+        if !success {
+            return Err(Socket::last_error());
+        }
+        match storage.get_addr() {
+            Some(addr) => Ok(addr),
+            None => Err(Error::unknown()),
+        }
+    }
+
+    //=======================================================================
+    // Process id of the peer of a connected AF_UNIX socket
+    pub fn peer_pid (&self) -> Result<u32, Error> {
+        let mut pid: u32 = 0;
+        let mut bytes: sys::DWORD = 0;
+        let success = unsafe {
+            sys::WSAIoctl(
+                self.to_raw(),
+                sys::SIO_AF_UNIX_GETPEERPID,
+                ptr::null_mut(),
+                0,
+                &mut pid as *mut _ as sys::LPVOID,
+                mem::size_of_val(&pid) as sys::DWORD,
+                &mut bytes,
+                ptr::null_mut(),
+                None
+            ) == 0
+        };
+
+        if success {
+            Ok(pid)
         }
+        else {
+            Err(Socket::last_error())
+        }
+    }
+
+    //=======================================================================
+    // The overlapped operations below take ownership of state. Buffers must
+    // be owned by the state's context so they outlive the operation.
+    //=======================================================================
+
+    //=======================================================================
+    pub fn accept_ex (
+        &self,
+        socket: sys::SOCKET,
+        buffer: sys::PVOID,
+        receive_bytes: u32,
+        addr_bytes: u32,
+        mut state: Box<queue::State>
+    ) -> Result<(), Error> {
+        state.set_socket(self.to_raw());
+
+        // Retrieve OS API
+        static ACCEPTEX: sys::WsaExtFn = sys::WsaExtFn {
+            guid: sys::WSAID_ACCEPTEX,
+            value: ATOMIC_USIZE_INIT,
+        };
+        let ptr = ACCEPTEX.get(self.to_raw());
+        let accept_ex: sys::FN_ACCEPTEX = unsafe { mem::transmute(ptr) };
+
+        // Call OS API
+        let success = accept_ex(
+            self.to_raw(),
+            socket,
+            buffer,
+            receive_bytes,
+            addr_bytes,
+            addr_bytes,
+            ptr::null_mut(),
+            state.overlapped_raw()
+        ) != 0;
+
+        Socket::submitted(success, state)
+    }
+
+    //=======================================================================
+    pub fn connect_ex (
+        &self,
+        remote: SocketAddr,
+        data: sys::PVOID,
+        data_bytes: u32,
+        state: Box<queue::State>
+    ) -> Result<(), Error> {
+        let mut storage = [0u8; sys::SOCKADDR_MAX_BYTES];
+        let (sockaddr, len) = Socket::sockaddr_from_addr(remote, &mut storage);
+        self.connect_raw_ex(sockaddr, len, data, data_bytes, state)
+    }
+
+    //=======================================================================
+    pub fn connect_raw_ex (
+        &self,
+        sockaddr: sys::PVOID,
+        len: i32,
+        data: sys::PVOID,
+        data_bytes: u32,
+        mut state: Box<queue::State>
+    ) -> Result<(), Error> {
+        state.set_socket(self.to_raw());
+
+        // Retrieve OS API
+        static CONNECTEX: sys::WsaExtFn = sys::WsaExtFn {
+            guid: sys::WSAID_CONNECTEX,
+            value: ATOMIC_USIZE_INIT,
+        };
+        let ptr = CONNECTEX.get(self.to_raw());
+        let connect_ex: sys::FN_CONNECTEX = unsafe { mem::transmute(ptr) };
+
+        // Call OS API
+        let success = connect_ex(
+            self.to_raw(),
+            sockaddr,
+            len,
+            data,
+            data_bytes,
+            ptr::null_mut(),
+            state.overlapped_raw()
+        ) != 0;
+
+        Socket::submitted(success, state)
+    }
+
+    //=======================================================================
+    pub fn receive_ex (&self, mut buf: sys::WSABUF, mut state: Box<queue::State>)
+        -> Result<(), Error>
+    {
+        state.set_socket(self.to_raw());
+
+        let mut flags: u32 = 0;
+        let success = unsafe {
+            sys::WSARecv(
+                self.to_raw(),
+                &mut buf as *mut _,
+                1,
+                ptr::null_mut(),
+                &mut flags as *mut _,
+                state.overlapped_raw(),
+                None
+            ) == 0
+        };
+
+        Socket::submitted(success, state)
+    }
+
+    //=======================================================================
+    pub fn send_ex (&self, mut buf: sys::WSABUF, mut state: Box<queue::State>)
+        -> Result<(), Error>
+    {
+        state.set_socket(self.to_raw());
+
+        let flags: u32 = 0;
+        let success = unsafe {
+            sys::WSASend(
+                self.to_raw(),
+                &mut buf as *mut _,
+                1,
+                ptr::null_mut(),
+                flags,
+                state.overlapped_raw(),
+                None
+            ) == 0
+        };
+
+        Socket::submitted(success, state)
+    }
+
+    //=======================================================================
+    pub fn receive_from_ex (
+        &self,
+        mut buf: sys::WSABUF,
+        addr: *mut sys::sockaddr_storage,
+        addr_bytes: *mut i32,
+        mut state: Box<queue::State>
+    ) -> Result<(), Error> {
+        state.set_socket(self.to_raw());
+
+        let mut flags: u32 = 0;
+        let success = unsafe {
+            sys::WSARecvFrom(
+                self.to_raw(),
+                &mut buf as *mut _,
+                1,
+                ptr::null_mut(),
+                &mut flags as *mut _,
+                addr as sys::PVOID,
+                addr_bytes,
+                state.overlapped_raw(),
+                None
+            ) == 0
+        };
+
+        Socket::submitted(success, state)
+    }
+
+    //=======================================================================
+    pub fn send_to_ex (
+        &self,
+        mut buf: sys::WSABUF,
+        addr: SocketAddr,
+        mut state: Box<queue::State>
+    ) -> Result<(), Error> {
+        state.set_socket(self.to_raw());
+
+        let mut storage = [0u8; sys::SOCKADDR_MAX_BYTES];
+        let (sockaddr, len) = Socket::sockaddr_from_addr(addr, &mut storage);
+
+        let flags: u32 = 0;
+        let success = unsafe {
+            sys::WSASendTo(
+                self.to_raw(),
+                &mut buf as *mut _,
+                1,
+                ptr::null_mut(),
+                flags,
+                sockaddr,
+                len,
+                state.overlapped_raw(),
+                None
+            ) == 0
+        };
+
+        Socket::submitted(success, state)
+    }
+
+    //=======================================================================
+    fn submitted (success: bool, state: Box<queue::State>) -> Result<(), Error> {
+        // Handle error
+        if !success {
+            let code = Socket::last_error_code();
+            if code != sys::ERROR_IO_PENDING {
+                let buffer = queue::abandon(state);
+                return Err(Error::from_os_error_code(code).with_buffer(buffer));
+            }
+        }
+
+        // Prevent deallocation of boxed state
+        let _ = Box::into_raw(state);
+        Ok(())
+    }
+
+    //=======================================================================
+    pub fn cancel (&self) {
+        unsafe {
+            sys::CancelIoEx(self.handle().to_raw(), ptr::null_mut())
+        };
     }
 
     //=======================================================================
@@ -211,11 +614,4 @@ impl Socket {
             },
         }
     }
-}
-
-impl Drop for Socket {
-    //=======================================================================
-    fn drop (&mut self) {
-        self.close();
-    }
 }
\ No newline at end of file
@@ -7,17 +7,22 @@
 *
 ***/
 
+use std::collections::VecDeque;
 use std::mem;
+use std::net as std_net;
+use std::os::windows::io::{AsRawSocket, IntoRawSocket, RawSocket};
 use std::ptr;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::ATOMIC_USIZE_INIT;
+use std::time::{Duration, Instant};
 
 use sys;
 use queue;
-use error::Error;
+use error::{Error, ErrorKind, Operation, OperationInfo};
 
+use super::cell::AddrCell;
 use super::socket::Socket;
-use super::addr::SocketAddr;
+use super::addr::{AddrFamily, IpAddr, SocketAddr, ToSocketAddrs};
 
 
 /****************************************************************************
@@ -28,14 +33,11 @@ use super::addr::SocketAddr;
 
 #[derive(Debug, Clone)]
 pub struct TcpListener {
-    inner: Arc<Mutex<TcpListenerInner>>,
+    inner: Arc<TcpListenerInner>,
 }
 
-unsafe impl Sync for TcpListener {}
-unsafe impl Send for TcpListener {}
-
 impl TcpListener {
-    pub fn addr (&self) -> SocketAddr { self.inner.lock().unwrap().addr }
+    pub fn addr (&self) -> SocketAddr { self.inner.addr }
 
     //=======================================================================
     pub fn new (addr: SocketAddr, queue: queue::Queue)
@@ -46,8 +48,33 @@ impl TcpListener {
         if let Err(error) = socket {
             return Err(error);
         }
-        let socket = socket.unwrap();
 
+        TcpListener::listen(socket.unwrap(), addr, false, queue)
+    }
+
+    //=======================================================================
+    // Listens on [::] accepting both IPv6 and IPv4 connections
+    pub fn new_dual_stack (port: u16, queue: queue::Queue)
+        -> Result<TcpListener, Error>
+    {
+        let socket = match Socket::new_from_family(AddrFamily::V6) {
+            Ok(socket) => socket,
+            Err(error) => return Err(error),
+        };
+
+        let v6_only: u32 = 0;
+        if let Err(error) = socket.set_option(sys::IPPROTO_IPV6, sys::IPV6_V6ONLY, &v6_only) {
+            return Err(error);
+        }
+
+        let addr = SocketAddr::new(IpAddr::new_unspecified(AddrFamily::V6), port);
+        TcpListener::listen(socket, addr, true, queue)
+    }
+
+    //=======================================================================
+    fn listen (socket: Socket, addr: SocketAddr, dual_stack: bool, queue: queue::Queue)
+        -> Result<TcpListener, Error>
+    {
         // Bind and listen
         if let Err(error) = socket.bind(addr) {
             return Err(error);
@@ -56,24 +83,89 @@ impl TcpListener {
             return Err(error);
         }
 
+        TcpListener::from_socket(socket, dual_stack, queue)
+    }
+
+    //=======================================================================
+    // Takes ownership of a listening socket created elsewhere, such as by
+    // another library or inherited from a parent process. The socket must
+    // have been created for overlapped I/O and must not already be
+    // associated with a completion port.
+    pub fn from_std (listener: std_net::TcpListener, queue: queue::Queue)
+        -> Result<TcpListener, Error>
+    {
+        unsafe { TcpListener::from_raw_socket(listener.into_raw_socket(), queue) }
+    }
+
+    //=======================================================================
+    // As from_std, for a socket that nothing else will close
+    pub unsafe fn from_raw_socket (socket: RawSocket, queue: queue::Queue)
+        -> Result<TcpListener, Error>
+    {
+        TcpListener::from_socket(Socket::from_raw(socket as sys::SOCKET), false, queue)
+    }
+
+    //=======================================================================
+    fn from_socket (socket: Socket, dual_stack: bool, queue: queue::Queue)
+        -> Result<TcpListener, Error>
+    {
+        // Get actual address in case an ephemeral port was requested
+        let addr = match socket.get_addr() {
+            Ok(addr) => addr,
+            Err(error) => return Err(error),
+        };
+
         // Associate with queue
         match queue::associate(&queue, socket.handle()) {
             Ok(..) => {
                 Ok(TcpListener {
-                    inner: Arc::new(Mutex::new(TcpListenerInner {
+                    inner: Arc::new(TcpListenerInner {
                         queue: queue,
                         socket: socket,
                         addr: addr,
-                    }))
+                        dual_stack: dual_stack,
+                    })
                 })
             },
             Err(error) => Err(error),
         }
     }
 
+    //=======================================================================
+    // Releases ownership of the socket. Fails if the listener has been
+    // cloned or has an accept outstanding. The socket remains associated
+    // with the queue.
+    pub fn into_raw_socket (self) -> Result<RawSocket, Error> {
+        match Arc::try_unwrap(self.inner) {
+            Ok(inner) => Ok(inner.socket.into_raw() as RawSocket),
+            Err(..) => Err(shared()),
+        }
+    }
+
     //=======================================================================
     pub fn accept (&self) -> Result<(), Error> {
-        self.inner.lock().unwrap().accept(self.clone())
+        self.inner.accept(self.clone())
+    }
+
+    //=======================================================================
+    // Accepts a connection and receives the first block of data sent by the
+    // client in the same operation. The accept does not complete until the
+    // client sends at least one byte (or closes the connection).
+    pub fn accept_with_data (&self, buffer: Box<[u8]>) -> Result<(), Error> {
+        self.inner.accept_with_data(self.clone(), buffer)
+    }
+
+    //=======================================================================
+    fn failed (&self, error: Error, submitted: Instant) -> Error {
+        let local = self.addr();
+        error.with_operation(OperationInfo::new(Operation::Accept, Some(local), None, submitted))
+    }
+}
+
+impl AsRawSocket for TcpListener {
+    //=======================================================================
+    fn as_raw_socket (&self) -> RawSocket {
+        self.inner.socket.to_raw() as RawSocket
     }
 }
 
@@ -82,6 +174,7 @@ struct TcpListenerInner {
     queue: queue::Queue,
     socket: Socket,
     addr: SocketAddr,
+    dual_stack: bool,
 }
 
 impl TcpListenerInner {
@@ -99,46 +192,110 @@ impl TcpListenerInner {
             listener: listener,
             socket: socket.unwrap(),
             addrs: AddrBuffers::new(),
+            submitted: Instant::now(),
         });
 
         // Get raw values from context for passing to OS API
         let socket = context.socket.to_raw();
         let addrs: sys::LPVOID = unsafe { mem::transmute(&context.addrs) };
 
-        // Create boxed state
+        // Create boxed state and call OS API
+        let state = Box::new(queue::State::new(context));
+        self.socket.accept_ex(
+            socket,
+            addrs,
+            0,
+            mem::size_of::<AddrBuffer>() as u32,
+            state
+        )
+    }
+
+    //=======================================================================
+    fn accept_with_data (&self, listener: TcpListener, buffer: Box<[u8]>)
+        -> Result<(), Error>
+    {
+        // Create socket
+        let socket = Socket::new_from_family(self.addr.family());
+        if let Err(error) = socket {
+            return Err(error);
+        }
+
+        // AcceptEx writes the received data followed by both addresses into
+        // a single contiguous buffer
+        let bytes = buffer.len();
+        let scratch = vec![0u8; bytes + mem::size_of::<AddrBuffers>()];
+
+        // Create boxed context
+        let context = Box::new(AcceptDataContext {
+            queue: self.queue.clone(),
+            listener: listener,
+            socket: socket.unwrap(),
+            buffer: buffer,
+            scratch: scratch.into_boxed_slice(),
+            submitted: Instant::now(),
+        });
+
+        // Get raw values from context for passing to OS API
+        let socket = context.socket.to_raw();
+        let scratch = context.scratch.as_ptr() as sys::LPVOID;
+
+        // Create boxed state and call OS API
         let state = Box::new(queue::State::new(context));
+        self.socket.accept_ex(
+            socket,
+            scratch,
+            bytes as u32,
+            mem::size_of::<AddrBuffer>() as u32,
+            state
+        )
+    }
 
+    //=======================================================================
+    fn get_accept_addrs (&self, buffer: &[u8], receive_bytes: usize)
+        -> (SocketAddr, SocketAddr)
+    {
         // Retrieve OS API
-        static ACCEPTEX: sys::WsaExtFn = sys::WsaExtFn {
-            guid: sys::WSAID_ACCEPTEX,
+        static GETACCEPTEXSOCKADDRS: sys::WsaExtFn = sys::WsaExtFn {
+            guid: sys::WSAID_GETACCEPTEXSOCKADDRS,
             value: ATOMIC_USIZE_INIT,
         };
-        let ptr = ACCEPTEX.get(self.socket.to_raw());
-        let accept_ex: sys::FN_ACCEPTEX = unsafe { mem::transmute(ptr) };
+        let ptr = GETACCEPTEXSOCKADDRS.get(self.socket.to_raw());
+        let get_addrs: sys::FN_GETACCEPTEXSOCKADDRS = unsafe { mem::transmute(ptr) };
 
         // Call OS API
-        let success = accept_ex(
-            self.socket.to_raw(),
-            socket,
-            addrs,
-            0,
+        let mut local: sys::PVOID = ptr::null_mut();
+        let mut local_len: i32 = 0;
+        let mut remote: sys::PVOID = ptr::null_mut();
+        let mut remote_len: i32 = 0;
+        get_addrs(
+            buffer.as_ptr() as sys::PVOID,
+            receive_bytes as u32,
             mem::size_of::<AddrBuffer>() as u32,
             mem::size_of::<AddrBuffer>() as u32,
-            ptr::null_mut(),
-            state.overlapped_raw()
-        ) != 0;
-
-        // Handle error
-        if !success {
-            let code = Socket::last_error_code();
-            if code != sys::ERROR_IO_PENDING {
-                return Err(Error::from_os_error_code(code));
-            }
-        }
+            &mut local,
+            &mut local_len,
+            &mut remote,
+            &mut remote_len
+        );
 
-        // Prevent deallocation of boxed state
-        let _ = Box::into_raw(state);
-        Ok(())
+        // Copy out addresses
+        let local = sys::sockaddr_storage::from_raw(local, local_len);
+        let remote = sys::sockaddr_storage::from_raw(remote, remote_len);
+        self.accepted_addrs(local.get_addr().unwrap(), remote.get_addr().unwrap())
+    }
+
+    //=======================================================================
+    // Reports IPv4 peers of a dual-stack listener as IPv4 rather than as
+    // v4-mapped IPv6 addresses
+    fn accepted_addrs (&self, local: SocketAddr, remote: SocketAddr)
+        -> (SocketAddr, SocketAddr)
+    {
+        if self.dual_stack {
+            (local.to_canonical(), remote.to_canonical())
+        }
+        else {
+            (local, remote)
+        }
     }
 }
 
@@ -151,15 +308,12 @@ impl TcpListenerInner {
 
 #[derive(Debug, Clone)]
 pub struct TcpStream {
-    inner: Arc<Mutex<TcpStreamInner>>,
+    inner: Arc<TcpStreamInner>,
 }
 
-unsafe impl Sync for TcpStream {}
-unsafe impl Send for TcpStream {}
-
 impl TcpStream {
-    pub fn addr_local (&self) -> SocketAddr { self.inner.lock().unwrap().local }
-    pub fn addr_remote (&self) -> SocketAddr { self.inner.lock().unwrap().remote }
+    pub fn addr_local (&self) -> SocketAddr { self.inner.local.get() }
+    pub fn addr_remote (&self) -> SocketAddr { self.inner.remote.get() }
 
     //=======================================================================
     pub fn new (local: SocketAddr, queue: queue::Queue)
@@ -181,32 +335,192 @@ impl TcpStream {
         match queue::associate(&queue, socket.handle()) {
             Ok(..) => {
                 Ok(TcpStream {
-                    inner: Arc::new(Mutex::new(TcpStreamInner {
+                    inner: Arc::new(TcpStreamInner {
                         queue: queue,
                         socket: socket,
-                        local: local,
-                        remote: SocketAddr::new_unspecified(local.family()),
-                    }))
+                        local: AddrCell::new(local),
+                        remote: AddrCell::new(SocketAddr::new_unspecified(local.family())),
+                    })
                 })
             },
             Err(error) => Err(error),
         }
     }
 
+    //=======================================================================
+    // Creates a stream bound to the wildcard address of the remote address
+    // family and connects it
+    pub fn connect_to (remote: SocketAddr, queue: queue::Queue)
+        -> Result<(), Error>
+    {
+        let local = SocketAddr::new_unspecified(remote.family());
+        match TcpStream::new(local, queue) {
+            Ok(stream) => stream.connect(remote),
+            Err(error) => Err(error),
+        }
+    }
+
+    //=======================================================================
+    // Races connection attempts to each address (RFC 8305). Attempts are
+    // started in turn, alternating address families and staggered by
+    // ATTEMPT_DELAY_MS, and the losers are cancelled. Completes with a single
+    // TcpConnect event for the winning stream, or for the last failure.
+    pub fn connect_any<A: ToSocketAddrs> (addrs: A, queue: queue::Queue)
+        -> Result<(), Error>
+    {
+        let addrs = match addrs.to_socket_addrs() {
+            Ok(addrs) => interleave(addrs),
+            Err(error) => return Err(error),
+        };
+
+        let race = Arc::new(Mutex::new(Race {
+            queue: queue::WeakQueue::new(&queue),
+            addrs: addrs,
+            attempts: Vec::new(),
+            generation: 0,
+            done: false,
+        }));
+
+        Race::start_next(&race)
+    }
+
+    //=======================================================================
+    fn from_accepted (
+        queue: queue::Queue,
+        socket: Socket,
+        local: SocketAddr,
+        remote: SocketAddr
+    ) -> Result<TcpStream, Error> {
+        // Associate with queue
+        if let Err(error) = queue::associate(&queue, socket.handle()) {
+            return Err(error);
+        }
+
+        Ok(TcpStream {
+            inner: Arc::new(TcpStreamInner {
+                queue: queue,
+                socket: socket,
+                local: AddrCell::new(local),
+                remote: AddrCell::new(remote),
+            }),
+        })
+    }
+
+    //=======================================================================
+    // Takes ownership of a connected socket created elsewhere. The same
+    // restrictions as TcpListener::from_std apply.
+    pub fn from_std (stream: std_net::TcpStream, queue: queue::Queue)
+        -> Result<TcpStream, Error>
+    {
+        unsafe { TcpStream::from_raw_socket(stream.into_raw_socket(), queue) }
+    }
+
+    //=======================================================================
+    // Takes ownership of a connected socket, such as one received with
+    // UnixStream::recv_with_fds, and associates it with queue. Fails if the
+    // socket is already associated with a completion port, which sockets
+    // from recv_with_fds never are. Nothing else may close the socket.
+    pub unsafe fn from_raw_socket (socket: RawSocket, queue: queue::Queue)
+        -> Result<TcpStream, Error>
+    {
+        let socket = Socket::from_raw(socket as sys::SOCKET);
+
+        let local = match socket.get_addr() {
+            Ok(addr) => addr,
+            Err(error) => return Err(error),
+        };
+        let remote = match socket.get_peer_addr() {
+            Ok(addr) => addr,
+            Err(error) => return Err(error),
+        };
+
+        TcpStream::from_accepted(queue, socket, local, remote)
+    }
+
+    //=======================================================================
+    // Releases ownership of the socket. Fails if the stream has been cloned
+    // or has operations outstanding.
+    pub fn into_raw_socket (self) -> Result<RawSocket, Error> {
+        match Arc::try_unwrap(self.inner) {
+            Ok(inner) => Ok(inner.socket.into_raw() as RawSocket),
+            Err(..) => Err(shared()),
+        }
+    }
+
     //=======================================================================
     pub fn connect (self, remote: SocketAddr) -> Result<(), Error> {
         let stream = self.clone();
-        self.inner.lock().unwrap().connect(stream, remote)
+        self.inner.connect(stream, remote, None)
+    }
+
+    //=======================================================================
+    pub fn connect_with_timeout (self, remote: SocketAddr, timeout: Duration)
+        -> Result<(), Error>
+    {
+        let stream = self.clone();
+        self.inner.connect(stream, remote, Some(timeout))
+    }
+
+    //=======================================================================
+    // Connects and sends the contents of buffer as part of the connection
+    // attempt. If fast open is enabled the data is carried in the SYN,
+    // otherwise it is sent as soon as the connection is established.
+    pub fn connect_with_data (self, remote: SocketAddr, buffer: Box<[u8]>)
+        -> Result<(), Error>
+    {
+        let stream = self.clone();
+        self.inner.connect_with_data(stream, remote, buffer)
+    }
+
+    //=======================================================================
+    // Must be called before connecting
+    pub fn set_fast_open (&self, enabled: bool) -> Result<(), Error> {
+        let value: sys::DWORD = if enabled { 1 } else { 0 };
+        self.inner.socket.set_option(
+            sys::IPPROTO_TCP,
+            sys::TCP_FASTOPEN,
+            &value
+        )
     }
 
     //=======================================================================
     pub fn receive (&self, buffer: Box<[u8]>) -> Result<(), Error> {
-        self.inner.lock().unwrap().receive(self.clone(), buffer)
+        self.inner.receive(self.clone(), buffer, None)
+    }
+
+    //=======================================================================
+    // Unlike receive, arming the deadline takes a lock shared by every timed
+    // operation on the queue
+    pub fn receive_with_timeout (&self, buffer: Box<[u8]>, timeout: Duration)
+        -> Result<(), Error>
+    {
+        self.inner.receive(self.clone(), buffer, Some(timeout))
     }
 
     //=======================================================================
     pub fn send (&self, buffer: Box<[u8]>) -> Result<(), Error> {
-        self.inner.lock().unwrap().send(self.clone(), buffer)
+        self.inner.send(self.clone(), buffer, None)
+    }
+
+    //=======================================================================
+    // Takes the queue's deadline lock, as receive_with_timeout does
+    pub fn send_with_timeout (&self, buffer: Box<[u8]>, timeout: Duration)
+        -> Result<(), Error>
+    {
+        self.inner.send(self.clone(), buffer, Some(timeout))
+    }
+
+    //=======================================================================
+    fn failed (&self, error: Error, operation: Operation, submitted: Instant) -> Error {
+        let (local, remote) = (assigned(self.addr_local()), assigned(self.addr_remote()));
+        error.with_operation(OperationInfo::new(operation, local, remote, submitted))
+    }
+}
+
+impl AsRawSocket for TcpStream {
+    //=======================================================================
+    fn as_raw_socket (&self) -> RawSocket {
+        self.inner.socket.to_raw() as RawSocket
     }
 }
 
@@ -214,121 +528,128 @@ impl TcpStream {
 pub struct TcpStreamInner {
     queue: queue::Queue,
     socket: Socket,
-    local: SocketAddr,
-    remote: SocketAddr,
+    local: AddrCell<SocketAddr>,
+    remote: AddrCell<SocketAddr>,
 }
 
 impl TcpStreamInner {
     //=======================================================================
-    pub fn connect (&mut self, stream: TcpStream, remote: SocketAddr) -> Result<(), Error> {
-        // Save remote address
-        self.remote = remote;
-
+    pub fn connect (
+        &self,
+        stream: TcpStream,
+        remote: SocketAddr,
+        timeout: Option<Duration>
+    ) -> Result<(), Error> {
         // Create state
         let state = Box::new(queue::State::new(Box::new(ConnectContext {
-            stream: stream
+            stream: stream,
+            submitted: Instant::now(),
         })));
 
-        // Build sockaddr
-        let mut storage = [0u8; sys::SOCKADDR_MAX_BYTES];
-        let (sockaddr, len) = Socket::sockaddr_from_addr(remote, &mut storage);
+        self.connect_ex(remote, ptr::null_mut(), 0, state, timeout)
+    }
 
-        // Retrieve OS API
-        static CONNECTEX: sys::WsaExtFn = sys::WsaExtFn {
-            guid: sys::WSAID_CONNECTEX,
-            value: ATOMIC_USIZE_INIT,
-        };
-        let ptr = CONNECTEX.get(self.socket.to_raw());
-        let connect_ex: sys::FN_CONNECTEX = unsafe { mem::transmute(ptr) };
+    //=======================================================================
+    fn connect_with_data (
+        &self,
+        stream: TcpStream,
+        remote: SocketAddr,
+        buffer: Box<[u8]>
+    ) -> Result<(), Error> {
+        // Create state
+        let bytes = buffer.len();
+        let data = buffer.as_ptr() as sys::PVOID;
+        let state = Box::new(queue::State::new(Box::new(ConnectDataContext {
+            stream: stream,
+            buffer: buffer,
+            submitted: Instant::now(),
+        })));
 
-        // Call OS API
-        let success = connect_ex(
-            self.socket.to_raw(),
-            sockaddr,
-            len,
-            ptr::null_mut(),
-            0,
-            ptr::null_mut(),
-            state.overlapped_raw()
-        ) != 0;
-
-        // Handle error
-        if !success {
-            let code = Socket::last_error_code();
-            if code != sys::ERROR_IO_PENDING {
-                return Err(Error::from_os_error_code(code));
-            }
+        self.connect_ex(remote, data, bytes as u32, state, None)
+    }
+
+    //=======================================================================
+    fn connect_ex (
+        &self,
+        remote: SocketAddr,
+        data: sys::PVOID,
+        data_bytes: u32,
+        mut state: Box<queue::State>,
+        timeout: Option<Duration>
+    ) -> Result<(), Error> {
+        // Claim remote address; it is exposed once the connect completes
+        if !self.remote.claim(remote) {
+            let buffer = queue::abandon(state);
+            let error = Error::new(ErrorKind::InvalidInput, "Stream has already been connected");
+            return Err(error.with_buffer(buffer));
         }
 
-        // Prevent deallocation of boxed state
-        let _ = Box::into_raw(state);
-        Ok(())
+        // Arm deadline
+        if let Some(timeout) = timeout {
+            queue::set_deadline(&self.queue, &mut state, self.socket.handle(), timeout);
+        }
+
+        // Nothing was submitted, so the stream may be connected again
+        let result = self.socket.connect_ex(remote, data, data_bytes, state);
+        if result.is_err() {
+            self.remote.release();
+        }
+        result
     }
 
     //=======================================================================
-    fn receive (&self, stream: TcpStream, mut buffer: Box<[u8]>) -> Result<(), Error> {
-        let mut buf = sys::WSABUF::new(&mut buffer[..]);
+    fn update_local (&self) -> Result<(), Error> {
+        match self.socket.get_addr() {
+            Ok(addr) => {
+                self.local.set(addr);
+                Ok(())
+            },
+            Err(e) => Err(e),
+        }
+    }
 
-        let state = Box::new(queue::State::new(Box::new(ReceiveContext {
+    //=======================================================================
+    fn receive (
+        &self,
+        stream: TcpStream,
+        mut buffer: Box<[u8]>,
+        timeout: Option<Duration>
+    ) -> Result<(), Error> {
+        let buf = sys::WSABUF::new(&mut buffer[..]);
+
+        let mut state = Box::new(queue::State::new(Box::new(ReceiveContext {
             stream: stream,
             buffer: buffer,
+            submitted: Instant::now(),
         })));
 
-        let mut flags: u32 = 0;
-        let success = unsafe {
-            sys::WSARecv(
-                self.socket.to_raw(),
-                &mut buf as *mut _,
-                1,
-                ptr::null_mut(),
-                &mut flags as *mut _,
-                state.overlapped_raw(),
-                None
-            ) == 0
-        };
-
-        if !success {
-            let code = Socket::last_error_code();
-            if code != sys::ERROR_IO_PENDING {
-                return Err(Error::from_os_error_code(code));
-            }
+        if let Some(timeout) = timeout {
+            queue::set_deadline(&self.queue, &mut state, self.socket.handle(), timeout);
         }
 
-        let _ = Box::into_raw(state);
-        Ok(())
+        self.socket.receive_ex(buf, state)
     }
 
     //=======================================================================
-    fn send (&self, stream: TcpStream, mut buffer: Box<[u8]>) -> Result<(), Error> {
-        let mut buf = sys::WSABUF::new(&mut buffer[..]);
-
-        let state = Box::new(queue::State::new(Box::new(SendContext {
+    fn send (
+        &self,
+        stream: TcpStream,
+        mut buffer: Box<[u8]>,
+        timeout: Option<Duration>
+    ) -> Result<(), Error> {
+        let buf = sys::WSABUF::new(&mut buffer[..]);
+
+        let mut state = Box::new(queue::State::new(Box::new(SendContext {
             stream: stream,
             buffer: buffer,
+            submitted: Instant::now(),
         })));
 
-        let flags: u32 = 0;
-        let success = unsafe {
-            sys::WSASend(
-                self.socket.to_raw(),
-                &mut buf as *mut _,
-                1,
-                ptr::null_mut(),
-                flags,
-                state.overlapped_raw(),
-                None
-            ) == 0
-        };
-
-        if !success {
-            let code = Socket::last_error_code();
-            if code != sys::ERROR_IO_PENDING {
-                return Err(Error::from_os_error_code(code));
-            }
+        if let Some(timeout) = timeout {
+            queue::set_deadline(&self.queue, &mut state, self.socket.handle(), timeout);
         }
 
-        let _ = Box::into_raw(state);
-        Ok(())
+        self.socket.send_ex(buf, state)
     }
 }
 
@@ -344,40 +665,80 @@ struct AcceptContext {
     listener: TcpListener,
     socket: Socket,
     addrs: AddrBuffers,
+    submitted: Instant,
 }
 
 impl queue::Context for AcceptContext {
     //=======================================================================
-    fn into_event (self: Box<Self>, _: u32) -> queue::Event {
-        let result = queue::associate(
-            &self.listener.inner.lock().unwrap().queue,
-            self.socket.handle()
+    fn into_event (self: Box<Self>, _: u32) -> Option<queue::Event> {
+        let (local, remote) = self.listener.inner.accepted_addrs(
+            self.addrs.local.addr.get_addr().unwrap(),
+            self.addrs.remote.addr.get_addr().unwrap()
         );
-        match result {
-            Err(e) => return queue::Event::TcpAccept(self.listener, Err(e)),
-            _ => {},
-        }
+        let result = TcpStream::from_accepted(self.queue, self.socket, local, remote);
 
-        let local = self.addrs.local.addr.get_addr().unwrap();
-        let remote = self.addrs.remote.addr.get_addr().unwrap();
-        let listener = self.listener.clone();
-        let queue = self.queue.clone();
+        Some(queue::Event::TcpAccept(self.listener, result))
+    }
 
-        let stream = TcpStream {
-            inner: Arc::new(Mutex::new(TcpStreamInner {
-                queue: queue,
-                socket: self.socket,
-                local: local,
-                remote: remote,
-            })),
-        };
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        let error = self.listener.failed(error, self.submitted);
+        Some(queue::Event::TcpAccept(self.listener, Err(error)))
+    }
+}
 
-        queue::Event::TcpAccept(listener, Ok(stream))
+
+/****************************************************************************
+*
+*   AcceptDataContext
+*
+***/
+
+struct AcceptDataContext {
+    queue: queue::Queue,
+    listener: TcpListener,
+    socket: Socket,
+    buffer: Box<[u8]>,
+    scratch: Box<[u8]>,
+    submitted: Instant,
+}
+
+impl queue::Context for AcceptDataContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, bytes: u32) -> Option<queue::Event> {
+        let context = *self;
+        let bytes = bytes as usize;
+        let mut buffer = context.buffer;
+
+        // Split received data and addresses out of the scratch buffer
+        let (local, remote) = context.listener.inner
+            .get_accept_addrs(&context.scratch[..], buffer.len());
+        buffer[..bytes].copy_from_slice(&context.scratch[..bytes]);
+
+        let result = TcpStream::from_accepted(
+            context.queue,
+            context.socket,
+            local,
+            remote
+        );
+
+        Some(queue::Event::TcpAcceptWithData(context.listener, result, buffer, bytes))
     }
 
     //=======================================================================
-    fn into_error (self: Box<Self>, _: u32) -> queue::Event {
-        queue::Event::TcpAccept(self.listener, Err(Socket::last_error()))
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        let error = self.listener.failed(error, self.submitted);
+        Some(queue::Event::TcpAcceptWithData(
+            self.listener,
+            Err(error),
+            self.buffer,
+            0
+        ))
+    }
+
+    //=======================================================================
+    fn into_buffer (self: Box<Self>) -> Option<Box<[u8]>> {
+        Some(self.buffer)
     }
 }
 
@@ -390,35 +751,79 @@ impl queue::Context for AcceptContext {
 
 struct ConnectContext {
     stream: TcpStream,
+    submitted: Instant,
 }
 
 impl queue::Context for ConnectContext {
     //=======================================================================
-    fn into_event (self: Box<Self>, _: u32) -> queue::Event {
-        let mut inner = self.stream.inner.lock().unwrap();
+    fn into_event (self: Box<Self>, _: u32) -> Option<queue::Event> {
+        // Expose remote address and get actual local address
+        self.stream.inner.remote.publish();
+        let result = self.stream.inner.update_local();
 
-        // Get actual local address
-        let result = match inner.socket.get_addr() {
-            Ok(addr) => {
-                inner.local = addr;
-                Ok(())
-            },
+        // Return event
+        Some(queue::Event::TcpConnect(
+            self.stream.clone(),
+            result
+        ))
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        self.stream.inner.remote.publish();
+        let error = self.stream.failed(error, Operation::Connect, self.submitted);
+        Some(queue::Event::TcpConnect(
+            self.stream.clone(),
+            Err(error)
+        ))
+    }
+}
+
+
+/****************************************************************************
+*
+*   ConnectDataContext
+*
+***/
+
+struct ConnectDataContext {
+    stream: TcpStream,
+    buffer: Box<[u8]>,
+    submitted: Instant,
+}
+
+impl queue::Context for ConnectDataContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, bytes: u32) -> Option<queue::Event> {
+        // Expose remote address and get actual local address
+        self.stream.inner.remote.publish();
+        let result = match self.stream.inner.update_local() {
+            Ok(()) => Ok(bytes as usize),
             Err(e) => Err(e),
         };
 
         // Return event
-        queue::Event::TcpConnect(
+        Some(queue::Event::TcpConnectWithData(
             self.stream.clone(),
+            self.buffer,
             result
-        )
+        ))
     }
 
     //=======================================================================
-    fn into_error (self: Box<Self>, _: u32) -> queue::Event {
-        queue::Event::TcpConnect(
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        self.stream.inner.remote.publish();
+        let error = self.stream.failed(error, Operation::Connect, self.submitted);
+        Some(queue::Event::TcpConnectWithData(
             self.stream.clone(),
-            Err(Socket::last_error())
-        )
+            self.buffer,
+            Err(error)
+        ))
+    }
+
+    //=======================================================================
+    fn into_buffer (self: Box<Self>) -> Option<Box<[u8]>> {
+        Some(self.buffer)
     }
 }
 
@@ -432,25 +837,32 @@ impl queue::Context for ConnectContext {
 struct ReceiveContext {
     stream: TcpStream,
     buffer: Box<[u8]>,
+    submitted: Instant,
 }
 
 impl queue::Context for ReceiveContext {
     //=======================================================================
-    fn into_event (self: Box<Self>, bytes: u32) -> queue::Event {
-        queue::Event::TcpReceive(
+    fn into_event (self: Box<Self>, bytes: u32) -> Option<queue::Event> {
+        Some(queue::Event::TcpReceive(
             self.stream.clone(),
             self.buffer,
             Ok(bytes as usize)
-        )
+        ))
     }
 
     //=======================================================================
-    fn into_error (self: Box<Self>, _: u32) -> queue::Event {
-        queue::Event::TcpReceive(
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        let error = self.stream.failed(error, Operation::Receive, self.submitted);
+        Some(queue::Event::TcpReceive(
             self.stream.clone(),
             self.buffer,
-            Err(Socket::last_error())
-        )
+            Err(error)
+        ))
+    }
+
+    //=======================================================================
+    fn into_buffer (self: Box<Self>) -> Option<Box<[u8]>> {
+        Some(self.buffer)
     }
 }
 
@@ -464,29 +876,292 @@ impl queue::Context for ReceiveContext {
 struct SendContext {
     stream: TcpStream,
     buffer: Box<[u8]>,
+    submitted: Instant,
 }
 
 impl queue::Context for SendContext {
     //=======================================================================
-    fn into_event (self: Box<Self>, _: u32) -> queue::Event {
-        queue::Event::TcpSend(
+    fn into_event (self: Box<Self>, _: u32) -> Option<queue::Event> {
+        Some(queue::Event::TcpSend(
             self.stream.clone(),
             self.buffer,
             Ok(())
-        )
+        ))
     }
 
     //=======================================================================
-    fn into_error (self: Box<Self>, _: u32) -> queue::Event {
-        queue::Event::TcpSend(
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        let error = self.stream.failed(error, Operation::Send, self.submitted);
+        Some(queue::Event::TcpSend(
             self.stream.clone(),
             self.buffer,
-            Err(Socket::last_error())
-        )
+            Err(error)
+        ))
+    }
+
+    //=======================================================================
+    fn into_buffer (self: Box<Self>) -> Option<Box<[u8]>> {
+        Some(self.buffer)
+    }
+}
+
+
+/****************************************************************************
+*
+*   Race
+*
+***/
+
+const ATTEMPT_DELAY_MS: u64 = 250;
+
+type RaceRef = Arc<Mutex<Race>>;
+
+// Holds the queue weakly because a pending RaceTimerContext, which refers
+// back to the race, is owned by the queue until it fires
+struct Race {
+    queue: queue::WeakQueue,
+    addrs: VecDeque<SocketAddr>,
+    attempts: Vec<TcpStream>,
+    generation: u64,
+    done: bool,
+}
+
+impl Race {
+    //=======================================================================
+    fn start_next (race: &RaceRef) -> Result<(), Error> {
+        let mut inner = race.lock().unwrap();
+        if inner.done {
+            return Ok(());
+        }
+
+        // Nothing can complete the race once the queue is gone
+        let queue = match inner.queue.upgrade() {
+            Some(queue) => queue,
+            None => return Err(Error::new(ErrorKind::Cancelled, "Queue has been dropped")),
+        };
+
+        let mut last_error = None;
+        while let Some(remote) = inner.addrs.pop_front() {
+            // Create stream
+            let local = SocketAddr::new_unspecified(remote.family());
+            let stream = match TcpStream::new(local, queue.clone()) {
+                Ok(stream) => stream,
+                Err(error) => {
+                    last_error = Some(error);
+                    continue;
+                },
+            };
+
+            // Connect
+            let state = Box::new(queue::State::new(Box::new(RaceConnectContext {
+                race: race.clone(),
+                stream: stream.clone(),
+                submitted: Instant::now(),
+            })));
+            let result = stream.inner.connect_ex(
+                remote,
+                ptr::null_mut(),
+                0,
+                state,
+                None
+            );
+            if let Err(error) = result {
+                last_error = Some(error);
+                continue;
+            }
+            inner.attempts.push(stream);
+            inner.generation += 1;
+
+            // Start the next attempt if this one is slow
+            if !inner.addrs.is_empty() {
+                let delay = Duration::from_millis(ATTEMPT_DELAY_MS);
+                let context = Box::new(RaceTimerContext {
+                    race: race.clone(),
+                    generation: inner.generation,
+                });
+                queue::schedule(&queue, delay, context);
+            }
+
+            return Ok(());
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "No addresses to connect to")
+        }))
+    }
+
+    //=======================================================================
+    fn remove (&mut self, stream: &TcpStream) {
+        self.attempts.retain(|a| !Arc::ptr_eq(&a.inner, &stream.inner));
+    }
+}
+
+
+/****************************************************************************
+*
+*   RaceConnectContext
+*
+***/
+
+struct RaceConnectContext {
+    race: RaceRef,
+    stream: TcpStream,
+    submitted: Instant,
+}
+
+impl queue::Context for RaceConnectContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, _: u32) -> Option<queue::Event> {
+        let mut race = self.race.lock().unwrap();
+        race.remove(&self.stream);
+        if race.done {
+            return None;
+        }
+
+        // Cancel the losers
+        race.done = true;
+        for attempt in race.attempts.drain(..) {
+            attempt.inner.socket.cancel();
+        }
+
+        // Expose remote address and get actual local address
+        self.stream.inner.remote.publish();
+        let result = self.stream.inner.update_local();
+
+        Some(queue::Event::TcpConnect(
+            self.stream.clone(),
+            result
+        ))
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        {
+            let mut race = self.race.lock().unwrap();
+            race.remove(&self.stream);
+            if race.done {
+                return None;
+            }
+        }
+
+        // Move on to the next address immediately
+        if Race::start_next(&self.race).is_ok() {
+            return None;
+        }
+
+        // Report the failure once no attempts remain
+        let mut race = self.race.lock().unwrap();
+        if race.done || !race.attempts.is_empty() {
+            return None;
+        }
+        race.done = true;
+
+        self.stream.inner.remote.publish();
+        let error = self.stream.failed(error, Operation::Connect, self.submitted);
+        Some(queue::Event::TcpConnect(
+            self.stream.clone(),
+            Err(error)
+        ))
     }
 }
 
 
+/****************************************************************************
+*
+*   RaceTimerContext
+*
+***/
+
+struct RaceTimerContext {
+    race: RaceRef,
+    generation: u64,
+}
+
+impl queue::Context for RaceTimerContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, _: u32) -> Option<queue::Event> {
+        {
+            // Ignore if another attempt was started since the timer was set
+            let race = self.race.lock().unwrap();
+            if race.done || race.generation != self.generation {
+                return None;
+            }
+        }
+
+        // Failures are reported by the attempt still in flight
+        let _ = Race::start_next(&self.race);
+        None
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, bytes: u32, _: Error) -> Option<queue::Event> {
+        self.into_event(bytes)
+    }
+}
+
+
+/****************************************************************************
+*
+*   Private functions
+*
+***/
+
+//===========================================================================
+fn shared () -> Error {
+    Error::new(ErrorKind::InvalidInput, "Socket is shared or has operations outstanding")
+}
+
+//===========================================================================
+// Addresses of a stream start out unspecified until bound or connected
+fn assigned (addr: SocketAddr) -> Option<SocketAddr> {
+    if addr.ip().is_unspecified() && addr.port() == 0 {
+        None
+    }
+    else {
+        Some(addr)
+    }
+}
+
+//===========================================================================
+fn is_v6 (addr: &SocketAddr) -> bool {
+    match *addr {
+        SocketAddr::V6(..) => true,
+        SocketAddr::V4(..) => false,
+    }
+}
+
+//===========================================================================
+// Alternates address families, starting with the family of the first
+// address, while keeping the order within each family
+fn interleave<I> (addrs: I) -> VecDeque<SocketAddr>
+    where I: Iterator<Item=SocketAddr>
+{
+    let addrs: Vec<SocketAddr> = addrs.collect();
+    let first_v6 = match addrs.first() {
+        Some(addr) => is_v6(addr),
+        None => return VecDeque::new(),
+    };
+
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.into_iter().partition(|addr| is_v6(addr) == first_v6);
+
+    let mut result = VecDeque::with_capacity(preferred.len() + other.len());
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => {
+                result.extend(a);
+                result.extend(b);
+            },
+        }
+    }
+
+    result
+}
+
+
 /****************************************************************************
 *
 *   AddrBuffer
@@ -530,4 +1205,572 @@ impl AddrBuffers {
             remote: AddrBuffer::new(),
         }
     }
-}
\ No newline at end of file
+}
+
+
+/****************************************************************************
+*
+*   Tests
+*
+***/
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net as std_net;
+    use std::os::windows::io::FromRawSocket;
+    use std::time::Duration;
+
+    use error::{ErrorKind, Operation};
+    use net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use queue::{Event, Queue};
+    use super::*;
+
+    //=======================================================================
+    fn loopback () -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0)
+    }
+
+    //=======================================================================
+    fn connected_pair (queue: &Queue) -> (TcpStream, TcpStream) {
+        let listener = TcpListener::new(loopback(), queue.clone()).unwrap();
+        listener.accept().unwrap();
+        TcpStream::connect_to(listener.addr(), queue.clone()).unwrap();
+
+        let mut server = None;
+        let mut client = None;
+        while server.is_none() || client.is_none() {
+            match queue.dequeue().unwrap() {
+                Event::TcpAccept(_, result) => server = Some(result.unwrap()),
+                Event::TcpConnect(stream, result) => {
+                    result.unwrap();
+                    client = Some(stream);
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+
+        (server.unwrap(), client.unwrap())
+    }
+
+    //=======================================================================
+    #[test]
+    fn accept_with_data () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let listener = TcpListener::new(loopback(), queue.clone()).unwrap();
+        listener.accept_with_data(Box::new([0u8; 64])).unwrap();
+        let mut client = std_net::TcpStream::connect(std_net::SocketAddr::from(listener.addr())).unwrap();
+        client.write_all(b"hello").unwrap();
+
+        // Data and both addresses are split out of the one AcceptEx buffer
+        match queue.dequeue().unwrap() {
+            Event::TcpAcceptWithData(_, result, buffer, bytes) => {
+                let stream = result.unwrap();
+                assert_eq!(&buffer[..bytes], b"hello");
+                assert_eq!(stream.addr_local(), listener.addr());
+                assert_eq!(stream.addr_remote(), SocketAddr::from(client.local_addr().unwrap()));
+            },
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn connect_with_data () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let listener = std_net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = SocketAddr::from(listener.local_addr().unwrap());
+        let stream = TcpStream::new(loopback(), queue.clone()).unwrap();
+        stream.connect_with_data(remote, Box::new(*b"hello")).unwrap();
+
+        match queue.dequeue().unwrap() {
+            Event::TcpConnectWithData(stream, buffer, result) => {
+                assert_eq!(result.unwrap(), 5);
+                assert_eq!(&buffer[..], b"hello");
+                assert_eq!(stream.addr_remote(), remote);
+
+                let (mut server, peer) = listener.accept().unwrap();
+                assert_eq!(stream.addr_local(), SocketAddr::from(peer));
+
+                let mut received = [0u8; 5];
+                server.read_exact(&mut received).unwrap();
+                assert_eq!(&received, b"hello");
+            },
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn connect_fast_open () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let listener = TcpListener::new(loopback(), queue.clone()).unwrap();
+        listener.accept_with_data(Box::new([0u8; 64])).unwrap();
+
+        let stream = TcpStream::new(loopback(), queue.clone()).unwrap();
+        stream.set_fast_open(true).unwrap();
+        stream.connect_with_data(listener.addr(), Box::new([1u8, 2, 3])).unwrap();
+
+        let mut accepted = false;
+        let mut connected = false;
+        while !accepted || !connected {
+            match queue.dequeue().unwrap() {
+                Event::TcpAcceptWithData(_, result, buffer, bytes) => {
+                    result.unwrap();
+                    assert_eq!(&buffer[..bytes], &[1, 2, 3]);
+                    accepted = true;
+                },
+                Event::TcpConnectWithData(_, _, result) => {
+                    assert_eq!(result.unwrap(), 3);
+                    connected = true;
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn interleave_families () {
+        let v4 = |n| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, n)), 80);
+        let v6 = |n| SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, n)), 80);
+
+        let addrs = vec![v6(1), v6(2), v6(3), v4(1), v4(2)];
+        let result: Vec<SocketAddr> = interleave(addrs.into_iter()).into_iter().collect();
+        assert_eq!(result, vec![v6(1), v4(1), v6(2), v4(2), v6(3)]);
+
+        let addrs = vec![v4(1), v6(1), v6(2)];
+        let result: Vec<SocketAddr> = interleave(addrs.into_iter()).into_iter().collect();
+        assert_eq!(result, vec![v4(1), v6(1), v6(2)]);
+    }
+
+    //=======================================================================
+    #[test]
+    fn connect_any_skips_refused () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        // Find a port nothing is listening on
+        let closed = TcpListener::new(loopback(), queue.clone()).unwrap().addr();
+
+        let listener = TcpListener::new(loopback(), queue.clone()).unwrap();
+        listener.accept().unwrap();
+        let addrs = vec![closed, listener.addr()];
+        TcpStream::connect_any(addrs, queue.clone()).unwrap();
+
+        let mut connected = false;
+        let mut accepted = false;
+        while !connected || !accepted {
+            match queue.dequeue().unwrap() {
+                Event::TcpAccept(_, result) => {
+                    result.unwrap();
+                    accepted = true;
+                },
+                Event::TcpConnect(stream, result) => {
+                    assert!(!connected);
+                    result.unwrap();
+                    assert_eq!(stream.addr_remote(), listener.addr());
+                    connected = true;
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn connect_any_releases_queue () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        // The first attempt wins before the timer for the second fires
+        let listener = TcpListener::new(loopback(), queue.clone()).unwrap();
+        listener.accept().unwrap();
+        let closed = TcpListener::new(loopback(), queue.clone()).unwrap().addr();
+        TcpStream::connect_any(vec![listener.addr(), closed], queue.clone()).unwrap();
+
+        let mut accepted = None;
+        let mut connected = None;
+        while accepted.is_none() || connected.is_none() {
+            match queue.dequeue().unwrap() {
+                Event::TcpAccept(_, result) => accepted = Some(result.unwrap()),
+                Event::TcpConnect(stream, result) => {
+                    result.unwrap();
+                    connected = Some(stream);
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+
+        // The pending timer must not keep the queue alive
+        let weak = queue::WeakQueue::new(&queue);
+        drop((accepted, connected, listener, queue));
+        assert!(weak.upgrade().is_none());
+    }
+
+    //=======================================================================
+    #[test]
+    fn receive_timeout () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+        let (_server, client) = connected_pair(&queue);
+
+        let buffer = Box::new([0u8; 16]);
+        client.receive_with_timeout(buffer, Duration::from_millis(50)).unwrap();
+
+        match queue.dequeue().unwrap() {
+            Event::TcpReceive(_, _, Err(error)) => {
+                assert_eq!(error.kind(), ErrorKind::TimedOut);
+            },
+            event => panic!("Expected timed out receive, got {:?}", event),
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn connect_refused_context () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        // Find a port nothing is listening on
+        let closed = TcpListener::new(loopback(), queue.clone()).unwrap().addr();
+        TcpStream::connect_to(closed, queue.clone()).unwrap();
+
+        match queue.dequeue().unwrap() {
+            Event::TcpConnect(_, Err(error)) => {
+                assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+
+                let operation = error.operation().unwrap();
+                assert_eq!(operation.operation, Operation::Connect);
+                assert_eq!(operation.remote, Some(closed));
+                assert!(error.to_string().contains("during connect"));
+
+                // The stream never got a local address
+                assert_eq!(operation.local, None);
+            },
+            event => panic!("Expected refused connect, got {:?}", event),
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn receive_reset () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+        let (server, client) = connected_pair(&queue);
+
+        // Closing with a zero linger timeout resets the connection
+        let linger = sys::linger { l_onoff: 1, l_linger: 0 };
+        server.inner.socket.set_option(sys::SOL_SOCKET, sys::SO_LINGER, &linger).unwrap();
+        client.receive(Box::new([0u8; 16])).unwrap();
+        drop(server);
+
+        // Reported with the Winsock code rather than the translated system one
+        match queue.dequeue().unwrap() {
+            Event::TcpReceive(_, _, Err(error)) => {
+                assert_eq!(error.os_error_code(), Some(sys::WSAECONNRESET));
+                assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+            },
+            event => panic!("Expected reset receive, got {:?}", event),
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn receive_before_timeout () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+        let (server, client) = connected_pair(&queue);
+
+        let buffer = Box::new([0u8; 16]);
+        client.receive_with_timeout(buffer, Duration::from_secs(10)).unwrap();
+        server.send(Box::new([1u8, 2, 3])).unwrap();
+
+        let mut received = false;
+        while !received {
+            match queue.dequeue().unwrap() {
+                Event::TcpSend(_, _, result) => result.unwrap(),
+                Event::TcpReceive(_, buffer, result) => {
+                    assert_eq!(result.unwrap(), 3);
+                    assert_eq!(&buffer[..3], &[1, 2, 3]);
+                    received = true;
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+    }
+
+    //=======================================================================
+    // Finds a link-local address of an adapter that is up
+    fn link_local () -> Option<SocketAddr> {
+        let mut buffer = vec![0u64; 16 * 1024];
+        let mut bytes = (buffer.len() * 8) as u32;
+        let code = unsafe {
+            sys::GetAdaptersAddresses(
+                sys::AF_INET6 as u32,
+                0,
+                ptr::null_mut(),
+                buffer.as_mut_ptr() as *mut sys::IP_ADAPTER_ADDRESSES,
+                &mut bytes
+            )
+        };
+        assert_eq!(code, 0);
+
+        let mut adapter = buffer.as_ptr() as *const sys::IP_ADAPTER_ADDRESSES;
+        while !adapter.is_null() {
+            let entry = unsafe { &*adapter };
+            adapter = entry.Next;
+            if entry.OperStatus != sys::IF_OPER_STATUS_UP {
+                continue;
+            }
+
+            let mut unicast = entry.FirstUnicastAddress as *const sys::IP_ADAPTER_UNICAST_ADDRESS;
+            while !unicast.is_null() {
+                let entry = unsafe { &*unicast };
+                unicast = entry.Next;
+
+                let addr = sys::sockaddr_storage::from_raw(
+                    entry.Address.lpSockaddr,
+                    entry.Address.iSockaddrLength
+                ).get_addr();
+                if let Some(SocketAddr::V6(addr)) = addr {
+                    if addr.ip().segments()[0] & 0xffc0 == 0xfe80 {
+                        return Some(SocketAddr::V6(addr));
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    //=======================================================================
+    fn scope_id (addr: SocketAddr) -> u32 {
+        match addr {
+            SocketAddr::V6(a) => a.scope_id(),
+            SocketAddr::V4(..) => panic!("Expected an IPv6 address"),
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn connect_link_local () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let local = match link_local() {
+            Some(local) => local,
+            None => return,
+        };
+        let scope = scope_id(local);
+        assert!(scope != 0);
+
+        let listener = TcpListener::new(local, queue.clone()).unwrap();
+        assert_eq!(scope_id(listener.addr()), scope);
+        listener.accept().unwrap();
+        TcpStream::connect_to(listener.addr(), queue.clone()).unwrap();
+
+        // Every address checked here is reported by the OS rather than
+        // echoed from the input
+        let mut accepted = false;
+        let mut connected = false;
+        while !accepted || !connected {
+            match queue.dequeue().unwrap() {
+                Event::TcpAccept(_, result) => {
+                    let stream = result.unwrap();
+                    assert_eq!(scope_id(stream.addr_local()), scope);
+                    assert_eq!(scope_id(stream.addr_remote()), scope);
+                    accepted = true;
+                },
+                Event::TcpConnect(stream, result) => {
+                    result.unwrap();
+                    assert_eq!(stream.addr_local().ip(), local.ip());
+                    assert_eq!(scope_id(stream.addr_local()), scope);
+                    connected = true;
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn dual_stack () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let listener = TcpListener::new_dual_stack(0, queue.clone()).unwrap();
+        let port = listener.addr().port();
+        assert!(listener.addr().ip().is_unspecified());
+
+        let v4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+        let v6 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), port);
+        for remote in [v4, v6].iter() {
+            listener.accept().unwrap();
+            TcpStream::connect_to(*remote, queue.clone()).unwrap();
+
+            let mut accepted = None;
+            let mut connected = false;
+            while accepted.is_none() || !connected {
+                match queue.dequeue().unwrap() {
+                    Event::TcpAccept(_, result) => accepted = Some(result.unwrap()),
+                    Event::TcpConnect(_, result) => {
+                        result.unwrap();
+                        connected = true;
+                    },
+                    event => panic!("Unexpected event {:?}", event),
+                }
+            }
+
+            // IPv4 peers are not reported as v4-mapped IPv6 addresses
+            let stream = accepted.unwrap();
+            assert_eq!(stream.addr_remote().ip(), remote.ip());
+            assert_eq!(stream.addr_local().ip(), remote.ip());
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn submit_failure_returns_buffer () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let stream = TcpStream::new(loopback(), queue).unwrap();
+        let mut error = stream.receive(Box::new([7u8; 4])).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::NotConnected);
+        assert_eq!(&error.take_buffer().unwrap()[..], &[7; 4]);
+    }
+
+    //=======================================================================
+    #[test]
+    fn listener_from_std () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let listener = std_net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = TcpListener::from_std(listener, queue.clone()).unwrap();
+        assert_eq!(listener.addr(), addr.into());
+
+        listener.accept().unwrap();
+        let mut client = std_net::TcpStream::connect(addr).unwrap();
+        let server = match queue.dequeue().unwrap() {
+            Event::TcpAccept(_, result) => result.unwrap(),
+            event => panic!("Unexpected event {:?}", event),
+        };
+
+        server.send(Box::new([1u8, 2, 3])).unwrap();
+        match queue.dequeue().unwrap() {
+            Event::TcpSend(_, _, result) => result.unwrap(),
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        let mut received = [0u8; 3];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, [1, 2, 3]);
+    }
+
+    //=======================================================================
+    #[test]
+    fn stream_from_std () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let listener = std_net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std_net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        let local: SocketAddr = client.local_addr().unwrap().into();
+        let stream = TcpStream::from_std(client, queue.clone()).unwrap();
+        assert_eq!(stream.addr_local(), local);
+        assert_eq!(stream.addr_remote(), SocketAddr::from(listener.local_addr().unwrap()));
+
+        stream.receive(Box::new([0u8; 16])).unwrap();
+        server.write_all(&[4, 5]).unwrap();
+        match queue.dequeue().unwrap() {
+            Event::TcpReceive(_, buffer, result) => {
+                assert_eq!(&buffer[..result.unwrap()], &[4, 5]);
+            },
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn into_raw_socket () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+        let (server, client) = connected_pair(&queue);
+        let raw = client.as_raw_socket();
+
+        // Not while another handle to the stream exists
+        let error = client.clone().into_raw_socket().err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+
+        assert_eq!(client.into_raw_socket().unwrap(), raw);
+        let stream = unsafe { std_net::TcpStream::from_raw_socket(raw) };
+        assert_eq!(SocketAddr::from(stream.peer_addr().unwrap()), server.addr_local());
+    }
+
+    //=======================================================================
+    #[test]
+    fn send_sync () {
+        fn assert_send_sync<T: Send + Sync> () {}
+        assert_send_sync::<TcpListener>();
+        assert_send_sync::<TcpStream>();
+    }
+
+    //=======================================================================
+    #[test]
+    fn connect_twice () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+        let (server, client) = connected_pair(&queue);
+        let remote = client.addr_remote();
+
+        // The remote address can only be replaced once
+        let error = client.clone().connect(server.addr_local()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert_eq!(client.addr_remote(), remote);
+
+        // The buffer comes back with the error
+        let buffer = Box::new([1u8, 2, 3]);
+        let mut error = client.clone().connect_with_data(server.addr_local(), buffer).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert_eq!(&error.take_buffer().unwrap()[..], &[1, 2, 3]);
+    }
+
+    //=======================================================================
+    #[test]
+    fn connect_after_submit_failure () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+        let listener = TcpListener::new(loopback(), queue.clone()).unwrap();
+        let stream = TcpStream::new(loopback(), queue.clone()).unwrap();
+
+        // Port 0 is rejected when submitting, which leaves no remote address
+        assert!(stream.clone().connect(loopback()).is_err());
+        assert!(stream.addr_remote().ip().is_unspecified());
+
+        listener.accept().unwrap();
+        stream.clone().connect(listener.addr()).unwrap();
+        let mut connected = false;
+        while !connected {
+            match queue.dequeue().unwrap() {
+                Event::TcpAccept(_, result) => { result.unwrap(); },
+                Event::TcpConnect(_, result) => {
+                    result.unwrap();
+                    connected = true;
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+        assert_eq!(stream.addr_remote(), listener.addr());
+    }
+}
@@ -0,0 +1,988 @@
+/****************************************************************************
+*
+*   net/udp.rs
+*   ioq
+*
+*   Copyright 2015 Tyler Cole
+*
+***/
+
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::windows::io::{AsRawSocket, RawSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use sys;
+use queue;
+use error::{Error, ErrorKind, Operation, OperationInfo};
+
+use super::cell::AddrCell;
+use super::socket::Socket;
+use super::addr::{AddrFamily, SocketAddr};
+
+
+/****************************************************************************
+*
+*   UdpSocket
+*
+***/
+
+#[derive(Debug, Clone)]
+pub struct UdpSocket {
+    inner: Arc<UdpSocketInner>,
+}
+
+impl UdpSocket {
+    pub fn addr_local (&self) -> SocketAddr { self.inner.local }
+    pub fn addr_remote (&self) -> Option<SocketAddr> { self.inner.remote.get() }
+
+    //=======================================================================
+    pub fn bind (addr: SocketAddr, queue: queue::Queue)
+        -> Result<UdpSocket, Error>
+    {
+        // Create socket
+        let socket = Socket::new_udp(addr.family());
+        if let Err(error) = socket {
+            return Err(error);
+        }
+        let socket = socket.unwrap();
+
+        // Bind
+        if let Err(error) = socket.bind(addr) {
+            return Err(error);
+        }
+
+        // Get actual address in case an ephemeral port was requested
+        let local = match socket.get_addr() {
+            Ok(local) => local,
+            Err(error) => return Err(error),
+        };
+
+        // Associate with queue
+        match queue::associate(&queue, socket.handle()) {
+            Ok(..) => {
+                Ok(UdpSocket {
+                    inner: Arc::new(UdpSocketInner {
+                        queue: queue,
+                        socket: socket,
+                        local: local,
+                        remote: AddrCell::new(None),
+                    })
+                })
+            },
+            Err(error) => Err(error),
+        }
+    }
+
+    //=======================================================================
+    // Sets the default destination for send and limits receiving to
+    // datagrams from remote. Completes immediately. A socket can only be
+    // connected once, so that reading its remote address never locks.
+    pub fn connect (&self, remote: SocketAddr) -> Result<(), Error> {
+        if !self.inner.remote.claim(Some(remote)) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Socket has already been connected"));
+        }
+
+        if let Err(error) = self.inner.socket.connect(remote) {
+            self.inner.remote.release();
+            return Err(error);
+        }
+
+        self.inner.remote.publish();
+        Ok(())
+    }
+
+    //=======================================================================
+    pub fn send_to (&self, buffer: Box<[u8]>, addr: SocketAddr) -> Result<(), Error> {
+        self.inner.send(self.clone(), buffer, Some(addr))
+    }
+
+    //=======================================================================
+    pub fn recv_from (&self, buffer: Box<[u8]>) -> Result<(), Error> {
+        self.inner.receive(self.clone(), buffer)
+    }
+
+    //=======================================================================
+    // Requires a prior connect
+    pub fn send (&self, buffer: Box<[u8]>) -> Result<(), Error> {
+        if self.inner.remote.get().is_none() {
+            return Err(not_connected());
+        }
+
+        self.inner.send(self.clone(), buffer, None)
+    }
+
+    //=======================================================================
+    // Requires a prior connect
+    pub fn recv (&self, buffer: Box<[u8]>) -> Result<(), Error> {
+        if self.inner.remote.get().is_none() {
+            return Err(not_connected());
+        }
+
+        self.inner.receive(self.clone(), buffer)
+    }
+
+    //=======================================================================
+    // Receives a datagram into every buffer, completing with a single
+    // Event::UdpReceiveBatch carrying a result per buffer once all of them
+    // have finished. Windows has no recvmmsg, and GSO/GRO style offloads
+    // are not available here, so each datagram is still its own overlapped
+    // receive; the batch saves events, not system calls.
+    pub fn recv_batch (&self, buffers: Vec<Box<[u8]>>) -> Result<(), Error> {
+        if buffers.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "No receive buffers"));
+        }
+
+        self.inner.receive_batch(self.clone(), buffers)
+    }
+
+    //=======================================================================
+    // Sends every datagram, completing with a single Event::UdpSendBatch
+    // carrying a result per datagram. As with recv_batch, there is no
+    // sendmmsg or GSO, so each datagram is its own overlapped send.
+    pub fn send_batch (&self, datagrams: Vec<(Box<[u8]>, SocketAddr)>) -> Result<(), Error> {
+        if datagrams.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "No datagrams to send"));
+        }
+
+        self.inner.send_batch(self.clone(), datagrams)
+    }
+
+    //=======================================================================
+    pub fn set_broadcast (&self, enabled: bool) -> Result<(), Error> {
+        let value: sys::DWORD = if enabled { 1 } else { 0 };
+        self.inner.socket.set_option(
+            sys::SOL_SOCKET,
+            sys::SO_BROADCAST,
+            &value
+        )
+    }
+
+    //=======================================================================
+    // Joins group on the interface with the given address, or on the
+    // default interface if it is unspecified
+    pub fn join_multicast_v4 (&self, group: &Ipv4Addr, interface: &Ipv4Addr)
+        -> Result<(), Error>
+    {
+        let mreq = sys::ip_mreq {
+            imr_multiaddr: group.octets(),
+            imr_interface: interface.octets(),
+        };
+        self.inner.socket.set_option(
+            sys::IPPROTO_IP,
+            sys::IP_ADD_MEMBERSHIP,
+            &mreq
+        )
+    }
+
+    //=======================================================================
+    pub fn leave_multicast_v4 (&self, group: &Ipv4Addr, interface: &Ipv4Addr)
+        -> Result<(), Error>
+    {
+        let mreq = sys::ip_mreq {
+            imr_multiaddr: group.octets(),
+            imr_interface: interface.octets(),
+        };
+        self.inner.socket.set_option(
+            sys::IPPROTO_IP,
+            sys::IP_DROP_MEMBERSHIP,
+            &mreq
+        )
+    }
+
+    //=======================================================================
+    // Joins group on the interface with the given index, or on the default
+    // interface if it is 0
+    pub fn join_multicast_v6 (&self, group: &Ipv6Addr, interface: u32)
+        -> Result<(), Error>
+    {
+        let mreq = sys::ipv6_mreq {
+            ipv6mr_multiaddr: group.octets(),
+            ipv6mr_interface: interface,
+        };
+        self.inner.socket.set_option(
+            sys::IPPROTO_IPV6,
+            sys::IPV6_ADD_MEMBERSHIP,
+            &mreq
+        )
+    }
+
+    //=======================================================================
+    pub fn leave_multicast_v6 (&self, group: &Ipv6Addr, interface: u32)
+        -> Result<(), Error>
+    {
+        let mreq = sys::ipv6_mreq {
+            ipv6mr_multiaddr: group.octets(),
+            ipv6mr_interface: interface,
+        };
+        self.inner.socket.set_option(
+            sys::IPPROTO_IPV6,
+            sys::IPV6_DROP_MEMBERSHIP,
+            &mreq
+        )
+    }
+
+    //=======================================================================
+    // Applies to the address family the socket is bound to
+    pub fn set_multicast_loop (&self, enabled: bool) -> Result<(), Error> {
+        let value: sys::DWORD = if enabled { 1 } else { 0 };
+        let inner = &self.inner;
+        match inner.local.family() {
+            AddrFamily::V4 => inner.socket.set_option(sys::IPPROTO_IP, sys::IP_MULTICAST_LOOP, &value),
+            AddrFamily::V6 => inner.socket.set_option(sys::IPPROTO_IPV6, sys::IPV6_MULTICAST_LOOP, &value),
+        }
+    }
+
+    //=======================================================================
+    // Sets the TTL, or the hop limit for IPv6, of outgoing multicast
+    pub fn set_multicast_ttl (&self, ttl: u32) -> Result<(), Error> {
+        let value: sys::DWORD = ttl;
+        let inner = &self.inner;
+        match inner.local.family() {
+            AddrFamily::V4 => inner.socket.set_option(sys::IPPROTO_IP, sys::IP_MULTICAST_TTL, &value),
+            AddrFamily::V6 => inner.socket.set_option(sys::IPPROTO_IPV6, sys::IPV6_MULTICAST_HOPS, &value),
+        }
+    }
+
+    //=======================================================================
+    fn failed (
+        &self,
+        error: Error,
+        operation: Operation,
+        remote: Option<SocketAddr>,
+        submitted: Instant
+    ) -> Error {
+        let info = OperationInfo::new(operation, Some(self.addr_local()), remote, submitted);
+        error.with_operation(info)
+    }
+}
+
+impl AsRawSocket for UdpSocket {
+    //=======================================================================
+    fn as_raw_socket (&self) -> RawSocket {
+        self.inner.socket.to_raw() as RawSocket
+    }
+}
+
+#[derive(Debug)]
+pub struct UdpSocketInner {
+    queue: queue::Queue,
+    socket: Socket,
+    local: SocketAddr,
+    remote: AddrCell<Option<SocketAddr>>,
+}
+
+impl UdpSocketInner {
+    //=======================================================================
+    fn receive (&self, socket: UdpSocket, mut buffer: Box<[u8]>)
+        -> Result<(), Error>
+    {
+        let buf = sys::WSABUF::new(&mut buffer[..]);
+
+        let mut context = Box::new(ReceiveContext {
+            socket: socket,
+            buffer: buffer,
+            addr: sys::sockaddr_storage::new(),
+            addr_bytes: mem::size_of::<sys::sockaddr_storage>() as i32,
+            submitted: Instant::now(),
+        });
+        let addr = &mut context.addr as *mut _;
+        let addr_bytes = &mut context.addr_bytes as *mut _;
+
+        let state = Box::new(queue::State::new(context));
+        self.socket.receive_from_ex(buf, addr, addr_bytes, state)
+    }
+
+    //=======================================================================
+    // Without recvmmsg, each buffer is its own overlapped receive sharing
+    // one batch; whichever finishes the batch produces the event
+    fn receive_batch (&self, socket: UdpSocket, buffers: Vec<Box<[u8]>>)
+        -> Result<(), Error>
+    {
+        let count = buffers.len();
+        let batch = Arc::new(ReceiveBatch {
+            socket: socket,
+            buffers: Mutex::new(buffers),
+            results: Mutex::new((0..count).map(|_| None).collect()),
+            remaining: AtomicUsize::new(count + 1),
+            submitted: Instant::now(),
+        });
+
+        // Buffers stay in the batch, so their contents do not move while
+        // the receives are in flight
+        let mut buffers = batch.buffers.lock().unwrap();
+        for (index, buffer) in buffers.iter_mut().enumerate() {
+            let buf = sys::WSABUF::new(&mut buffer[..]);
+            let mut context = Box::new(ReceiveBatchContext {
+                batch: batch.clone(),
+                index: index,
+                addr: sys::sockaddr_storage::new(),
+                addr_bytes: mem::size_of::<sys::sockaddr_storage>() as i32,
+            });
+            let addr = &mut context.addr as *mut _;
+            let addr_bytes = &mut context.addr_bytes as *mut _;
+
+            let state = Box::new(queue::State::new(context));
+            if let Err(error) = self.socket.receive_from_ex(buf, addr, addr_bytes, state) {
+                let error = batch.failed(error);
+                batch.results.lock().unwrap()[index] = Some(Err(error));
+                batch.remaining.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        drop(buffers);
+
+        // Release the submission's hold on the batch
+        if batch.remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+            return queue::post(&self.queue, Box::new(ReceiveBatchDoneContext {
+                batch: batch,
+            }));
+        }
+
+        Ok(())
+    }
+
+    //=======================================================================
+    // Without sendmmsg, each datagram is its own overlapped send sharing
+    // one batch; whichever finishes the batch produces the event
+    fn send_batch (&self, socket: UdpSocket, datagrams: Vec<(Box<[u8]>, SocketAddr)>)
+        -> Result<(), Error>
+    {
+        let count = datagrams.len();
+        let batch = Arc::new(SendBatch {
+            socket: socket,
+            datagrams: Mutex::new(datagrams),
+            results: Mutex::new((0..count).map(|_| None).collect()),
+            remaining: AtomicUsize::new(count + 1),
+            submitted: Instant::now(),
+        });
+
+        // Buffers stay in the batch, so their contents do not move while
+        // the sends are in flight
+        let mut datagrams = batch.datagrams.lock().unwrap();
+        for (index, &mut (ref mut buffer, addr)) in datagrams.iter_mut().enumerate() {
+            let buf = sys::WSABUF::new(&mut buffer[..]);
+            let state = Box::new(queue::State::new(Box::new(SendBatchContext {
+                batch: batch.clone(),
+                index: index,
+                addr: addr,
+            })));
+
+            if let Err(error) = self.socket.send_to_ex(buf, addr, state) {
+                let error = batch.socket.failed(error, Operation::Send, Some(addr), batch.submitted);
+                batch.results.lock().unwrap()[index] = Some(Err(error));
+                batch.remaining.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        drop(datagrams);
+
+        // Release the submission's hold on the batch
+        if batch.remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+            return queue::post(&self.queue, Box::new(SendBatchDoneContext {
+                batch: batch,
+            }));
+        }
+
+        Ok(())
+    }
+
+    //=======================================================================
+    // Sends to addr, or to the connected address if addr is None
+    fn send (&self, socket: UdpSocket, mut buffer: Box<[u8]>, addr: Option<SocketAddr>)
+        -> Result<(), Error>
+    {
+        let buf = sys::WSABUF::new(&mut buffer[..]);
+
+        let state = Box::new(queue::State::new(Box::new(SendContext {
+            socket: socket,
+            buffer: buffer,
+            remote: addr.or(self.remote.get()),
+            submitted: Instant::now(),
+        })));
+
+        match addr {
+            Some(addr) => self.socket.send_to_ex(buf, addr, state),
+            None => self.socket.send_ex(buf, state),
+        }
+    }
+}
+
+
+/****************************************************************************
+*
+*   ReceiveContext
+*
+***/
+
+struct ReceiveContext {
+    socket: UdpSocket,
+    buffer: Box<[u8]>,
+    addr: sys::sockaddr_storage,
+    addr_bytes: i32,
+    submitted: Instant,
+}
+
+impl queue::Context for ReceiveContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, bytes: u32) -> Option<queue::Event> {
+        let result = match self.addr.get_addr() {
+            Some(addr) => Ok((bytes as usize, addr)),
+            None => Err(Error::new(ErrorKind::Unknown, "Unknown sender address family")),
+        };
+
+        Some(queue::Event::UdpReceive(
+            self.socket.clone(),
+            self.buffer,
+            result
+        ))
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        let remote = self.socket.addr_remote();
+        let error = self.socket.failed(error, Operation::Receive, remote, self.submitted);
+        Some(queue::Event::UdpReceive(
+            self.socket.clone(),
+            self.buffer,
+            Err(error)
+        ))
+    }
+
+    //=======================================================================
+    fn into_buffer (self: Box<Self>) -> Option<Box<[u8]>> {
+        Some(self.buffer)
+    }
+}
+
+
+/****************************************************************************
+*
+*   SendContext
+*
+***/
+
+struct SendContext {
+    socket: UdpSocket,
+    buffer: Box<[u8]>,
+    remote: Option<SocketAddr>,
+    submitted: Instant,
+}
+
+impl queue::Context for SendContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, bytes: u32) -> Option<queue::Event> {
+        Some(queue::Event::UdpSend(
+            self.socket.clone(),
+            self.buffer,
+            Ok(bytes as usize)
+        ))
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        let error = self.socket.failed(error, Operation::Send, self.remote, self.submitted);
+        Some(queue::Event::UdpSend(
+            self.socket.clone(),
+            self.buffer,
+            Err(error)
+        ))
+    }
+
+    //=======================================================================
+    fn into_buffer (self: Box<Self>) -> Option<Box<[u8]>> {
+        Some(self.buffer)
+    }
+}
+
+
+/****************************************************************************
+*
+*   ReceiveBatch
+*
+***/
+
+struct ReceiveBatch {
+    socket: UdpSocket,
+    buffers: Mutex<Vec<Box<[u8]>>>,
+    results: Mutex<Vec<Option<Result<(usize, SocketAddr), Error>>>>,
+    remaining: AtomicUsize,
+    submitted: Instant,
+}
+
+impl ReceiveBatch {
+    //=======================================================================
+    fn complete (&self, index: usize, result: Result<(usize, SocketAddr), Error>)
+        -> Option<queue::Event>
+    {
+        self.results.lock().unwrap()[index] = Some(result);
+        match self.remaining.fetch_sub(1, Ordering::SeqCst) {
+            1 => Some(self.into_event()),
+            _ => None,
+        }
+    }
+
+    //=======================================================================
+    fn failed (&self, error: Error) -> Error {
+        let remote = self.socket.addr_remote();
+        self.socket.failed(error, Operation::Receive, remote, self.submitted)
+    }
+
+    //=======================================================================
+    fn into_event (&self) -> queue::Event {
+        let buffers = mem::replace(&mut *self.buffers.lock().unwrap(), Vec::new());
+        let results = mem::replace(&mut *self.results.lock().unwrap(), Vec::new());
+        queue::Event::UdpReceiveBatch(
+            self.socket.clone(),
+            buffers,
+            results.into_iter().map(|r| r.unwrap()).collect()
+        )
+    }
+}
+
+
+/****************************************************************************
+*
+*   ReceiveBatchContext
+*
+***/
+
+struct ReceiveBatchContext {
+    batch: Arc<ReceiveBatch>,
+    index: usize,
+    addr: sys::sockaddr_storage,
+    addr_bytes: i32,
+}
+
+impl queue::Context for ReceiveBatchContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, bytes: u32) -> Option<queue::Event> {
+        let result = match self.addr.get_addr() {
+            Some(addr) => Ok((bytes as usize, addr)),
+            None => Err(Error::new(ErrorKind::Unknown, "Unknown sender address family")),
+        };
+
+        self.batch.complete(self.index, result)
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        let error = self.batch.failed(error);
+        self.batch.complete(self.index, Err(error))
+    }
+}
+
+
+/****************************************************************************
+*
+*   ReceiveBatchDoneContext
+*
+***/
+
+// Posted when every receive fails to submit, before receive_batch releases
+// its hold on the batch
+struct ReceiveBatchDoneContext {
+    batch: Arc<ReceiveBatch>,
+}
+
+impl queue::Context for ReceiveBatchDoneContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, _: u32) -> Option<queue::Event> {
+        Some(self.batch.into_event())
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, _: Error) -> Option<queue::Event> {
+        Some(self.batch.into_event())
+    }
+}
+
+
+/****************************************************************************
+*
+*   SendBatch
+*
+***/
+
+struct SendBatch {
+    socket: UdpSocket,
+    datagrams: Mutex<Vec<(Box<[u8]>, SocketAddr)>>,
+    results: Mutex<Vec<Option<Result<usize, Error>>>>,
+    remaining: AtomicUsize,
+    submitted: Instant,
+}
+
+impl SendBatch {
+    //=======================================================================
+    fn complete (&self, index: usize, result: Result<usize, Error>) -> Option<queue::Event> {
+        self.results.lock().unwrap()[index] = Some(result);
+        match self.remaining.fetch_sub(1, Ordering::SeqCst) {
+            1 => Some(self.into_event()),
+            _ => None,
+        }
+    }
+
+    //=======================================================================
+    fn into_event (&self) -> queue::Event {
+        let datagrams = mem::replace(&mut *self.datagrams.lock().unwrap(), Vec::new());
+        let results = mem::replace(&mut *self.results.lock().unwrap(), Vec::new());
+        queue::Event::UdpSendBatch(
+            self.socket.clone(),
+            datagrams,
+            results.into_iter().map(|r| r.unwrap()).collect()
+        )
+    }
+}
+
+
+/****************************************************************************
+*
+*   SendBatchContext
+*
+***/
+
+struct SendBatchContext {
+    batch: Arc<SendBatch>,
+    index: usize,
+    addr: SocketAddr,
+}
+
+impl queue::Context for SendBatchContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, bytes: u32) -> Option<queue::Event> {
+        self.batch.complete(self.index, Ok(bytes as usize))
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        let batch = &self.batch;
+        let error = batch.socket.failed(error, Operation::Send, Some(self.addr), batch.submitted);
+        batch.complete(self.index, Err(error))
+    }
+}
+
+
+/****************************************************************************
+*
+*   SendBatchDoneContext
+*
+***/
+
+// Posted when every send finishes, or fails to submit, before send_batch
+// releases its hold on the batch
+struct SendBatchDoneContext {
+    batch: Arc<SendBatch>,
+}
+
+impl queue::Context for SendBatchDoneContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, _: u32) -> Option<queue::Event> {
+        Some(self.batch.into_event())
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, _: Error) -> Option<queue::Event> {
+        Some(self.batch.into_event())
+    }
+}
+
+
+/****************************************************************************
+*
+*   Private functions
+*
+***/
+
+//===========================================================================
+fn not_connected () -> Error {
+    Error::new(ErrorKind::InvalidInput, "UDP socket is not connected")
+}
+
+
+/****************************************************************************
+*
+*   Tests
+*
+***/
+
+#[cfg(test)]
+mod tests {
+    use std::net as std_net;
+    use std::thread;
+    use std::time::Duration;
+
+    use sys;
+    use error::{ErrorKind, Operation};
+    use net::{self, IpAddr, Ipv4Addr, SocketAddr};
+    use queue::{Event, Queue};
+    use super::*;
+
+    //=======================================================================
+    fn loopback () -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0)
+    }
+
+    //=======================================================================
+    #[test]
+    fn send_to_recv_from () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let a = UdpSocket::bind(loopback(), queue.clone()).unwrap();
+        let b = UdpSocket::bind(loopback(), queue.clone()).unwrap();
+        assert!(a.addr_local().port() != 0);
+
+        b.recv_from(Box::new([0u8; 16])).unwrap();
+        a.send_to(Box::new([1u8, 2, 3]), b.addr_local()).unwrap();
+
+        let mut sent = false;
+        let mut received = false;
+        while !sent || !received {
+            match queue.dequeue().unwrap() {
+                Event::UdpSend(_, _, result) => {
+                    assert_eq!(result.unwrap(), 3);
+                    sent = true;
+                },
+                Event::UdpReceive(socket, buffer, result) => {
+                    let (bytes, from) = result.unwrap();
+                    assert_eq!(socket.addr_local(), b.addr_local());
+                    assert_eq!(&buffer[..bytes], &[1, 2, 3]);
+                    assert_eq!(from, a.addr_local());
+                    received = true;
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn connected () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let a = UdpSocket::bind(loopback(), queue.clone()).unwrap();
+        let b = UdpSocket::bind(loopback(), queue.clone()).unwrap();
+
+        // Unconnected sockets have no default destination
+        assert!(a.send(Box::new([0u8])).is_err());
+        assert!(a.recv(Box::new([0u8])).is_err());
+
+        a.connect(b.addr_local()).unwrap();
+        b.connect(a.addr_local()).unwrap();
+        assert_eq!(a.addr_remote(), Some(b.addr_local()));
+
+        // The remote address is set once
+        let error = a.connect(a.addr_local()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert_eq!(a.addr_remote(), Some(b.addr_local()));
+
+        b.recv(Box::new([0u8; 16])).unwrap();
+        a.send(Box::new([4u8, 5])).unwrap();
+
+        let mut received = false;
+        while !received {
+            match queue.dequeue().unwrap() {
+                Event::UdpSend(_, _, result) => assert_eq!(result.unwrap(), 2),
+                Event::UdpReceive(_, buffer, result) => {
+                    let (bytes, from) = result.unwrap();
+                    assert_eq!(&buffer[..bytes], &[4, 5]);
+                    assert_eq!(from, a.addr_local());
+                    received = true;
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn receive_after_unreachable () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let a = UdpSocket::bind(loopback(), queue.clone()).unwrap();
+        let closed = {
+            let socket = std_net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            SocketAddr::from(socket.local_addr().unwrap())
+        };
+
+        // Draws an ICMP port unreachable
+        a.send_to(Box::new([1u8]), closed).unwrap();
+        match queue.dequeue().unwrap() {
+            Event::UdpSend(_, _, result) => assert_eq!(result.unwrap(), 1),
+            event => panic!("Unexpected event {:?}", event),
+        }
+        thread::sleep(Duration::from_millis(50));
+
+        a.recv_from(Box::new([0u8; 16])).unwrap();
+        let b = std_net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        b.send_to(&[2, 3], std_net::SocketAddr::from(a.addr_local())).unwrap();
+
+        match queue.dequeue().unwrap() {
+            Event::UdpReceive(_, buffer, result) => {
+                let (bytes, _) = result.unwrap();
+                assert_eq!(&buffer[..bytes], &[2, 3]);
+            },
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn multicast_loopback () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+        let group = Ipv4Addr::new(239, 255, 42, 99);
+        let interface = Ipv4Addr::new(127, 0, 0, 1);
+
+        let receiver = UdpSocket::bind(
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
+            queue.clone()
+        ).unwrap();
+        receiver.join_multicast_v4(&group, &interface).unwrap();
+
+        // Send out of the loopback interface and loop back to ourselves
+        let sender = UdpSocket::bind(loopback(), queue.clone()).unwrap();
+        sender.inner.socket.set_option(
+            sys::IPPROTO_IP,
+            sys::IP_MULTICAST_IF,
+            &interface.octets()
+        ).unwrap();
+        sender.set_multicast_loop(true).unwrap();
+        sender.set_multicast_ttl(1).unwrap();
+
+        let port = receiver.addr_local().port();
+        receiver.recv_from(Box::new([0u8; 16])).unwrap();
+        sender.send_to(Box::new([7u8, 8, 9]), SocketAddr::new(IpAddr::V4(group), port)).unwrap();
+
+        let mut received = false;
+        while !received {
+            match queue.dequeue().unwrap() {
+                Event::UdpSend(_, _, result) => assert_eq!(result.unwrap(), 3),
+                Event::UdpReceive(_, buffer, result) => {
+                    let (bytes, from) = result.unwrap();
+                    assert_eq!(&buffer[..bytes], &[7, 8, 9]);
+                    assert_eq!(from, sender.addr_local());
+                    received = true;
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+
+        receiver.leave_multicast_v4(&group, &interface).unwrap();
+
+        // Leaving a group that was not joined fails
+        assert!(receiver.leave_multicast_v4(&group, &interface).is_err());
+    }
+
+    //=======================================================================
+    #[test]
+    fn broadcast () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let socket = UdpSocket::bind(loopback(), queue.clone()).unwrap();
+        socket.set_broadcast(true).unwrap();
+        socket.set_broadcast(false).unwrap();
+    }
+
+    //=======================================================================
+    #[test]
+    fn batch () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let a = UdpSocket::bind(loopback(), queue.clone()).unwrap();
+        let b = UdpSocket::bind(loopback(), queue.clone()).unwrap();
+
+        let buffers = (0..5).map(|_| vec![0u8; 16].into_boxed_slice()).collect();
+        b.recv_batch(buffers).unwrap();
+
+        let datagrams: Vec<(Box<[u8]>, SocketAddr)> = (0..5u8).map(|i| {
+            (vec![i; i as usize + 1].into_boxed_slice(), b.addr_local())
+        }).collect();
+        a.send_batch(datagrams).unwrap();
+
+        let mut sent = false;
+        let mut received = false;
+        while !sent || !received {
+            match queue.dequeue().unwrap() {
+                Event::UdpSendBatch(_, datagrams, results) => {
+                    assert_eq!(datagrams.len(), 5);
+                    for (i, result) in results.into_iter().enumerate() {
+                        assert_eq!(result.unwrap(), i + 1);
+                    }
+                    sent = true;
+                },
+                Event::UdpReceiveBatch(_, buffers, results) => {
+                    // Loopback datagrams arrive in order, filling receives
+                    // in the order they were posted
+                    assert_eq!(results.len(), 5);
+                    for (i, result) in results.into_iter().enumerate() {
+                        let (bytes, from) = result.unwrap();
+                        assert_eq!(from, a.addr_local());
+                        assert_eq!(&buffers[i][..bytes], &vec![i as u8; i + 1][..]);
+                    }
+                    received = true;
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn batch_reports_oversized () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let a = std_net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind(loopback(), queue.clone()).unwrap();
+
+        let buffers = (0..3).map(|_| vec![0u8; 16].into_boxed_slice()).collect();
+        b.recv_batch(buffers).unwrap();
+
+        let to = std_net::SocketAddr::from(b.addr_local());
+        a.send_to(&[1; 4], to).unwrap();
+        a.send_to(&[2; 32], to).unwrap();
+        a.send_to(&[3; 4], to).unwrap();
+
+        // The datagram too large for its buffer fails only its own receive
+        match queue.dequeue().unwrap() {
+            Event::UdpReceiveBatch(_, buffers, results) => {
+                assert_eq!(results[0].as_ref().unwrap().0, 4);
+                assert!(results[1].is_err());
+                let (bytes, _) = *results[2].as_ref().unwrap();
+                assert_eq!(&buffers[2][..bytes], &[3; 4]);
+            },
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn batch_submit_failure () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let socket = UdpSocket::bind(loopback(), queue.clone()).unwrap();
+        assert!(socket.recv_batch(Vec::new()).is_err());
+        assert!(socket.send_batch(Vec::new()).is_err());
+
+        // Port 0 is rejected when submitting, yet still yields one event
+        let datagrams = vec![(vec![1u8].into_boxed_slice(), loopback())];
+        socket.send_batch(datagrams).unwrap();
+        match queue.dequeue().unwrap() {
+            Event::UdpSendBatch(_, datagrams, results) => {
+                assert_eq!(datagrams.len(), 1);
+                let error = results[0].as_ref().err().unwrap();
+                let operation = error.operation().unwrap();
+                assert_eq!(operation.operation, Operation::Send);
+                assert_eq!(operation.local, Some(socket.addr_local()));
+                assert_eq!(operation.remote, Some(loopback()));
+            },
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+}
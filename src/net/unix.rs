@@ -0,0 +1,1261 @@
+/****************************************************************************
+*
+*   net/unix.rs
+*   ioq
+*
+*   Copyright 2015 Tyler Cole
+*
+***/
+
+use std::cmp;
+use std::env;
+use std::fs;
+use std::mem;
+use std::os::windows::io::{AsRawSocket, RawSocket};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use sys;
+use queue;
+use error::{Error, ErrorKind};
+
+use super::cell::AddrCell;
+use super::socket::Socket;
+
+
+/****************************************************************************
+*
+*   Constants
+*
+***/
+
+const PATH_OFFSET: usize = 2; // Offset of sun_path in sockaddr_un
+
+const FD_HEADER_BYTES: usize = 8;
+const INFO_BYTES: usize = mem::size_of::<sys::WSAPROTOCOL_INFOW>();
+const MAX_FDS: usize = 253;
+const MAX_FD_DATA_BYTES: usize = 1 << 24;
+
+
+/****************************************************************************
+*
+*   UnixAddr
+*
+***/
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum UnixAddr {
+    Unnamed,
+    Path(PathBuf),
+    Abstract(Vec<u8>),
+}
+
+impl UnixAddr {
+    //=======================================================================
+    pub fn new<P: AsRef<Path>> (path: P) -> UnixAddr {
+        UnixAddr::Path(path.as_ref().to_path_buf())
+    }
+
+    //=======================================================================
+    // Names in the abstract namespace have no filesystem presence
+    pub fn new_abstract (name: &[u8]) -> UnixAddr {
+        UnixAddr::Abstract(name.to_vec())
+    }
+
+    //=======================================================================
+    pub fn is_unnamed (&self) -> bool {
+        match *self {
+            UnixAddr::Unnamed => true,
+            _ => false,
+        }
+    }
+
+    //=======================================================================
+    pub fn as_path (&self) -> Option<&Path> {
+        match *self {
+            UnixAddr::Path(ref path) => Some(path),
+            _ => None,
+        }
+    }
+
+    //=======================================================================
+    fn to_raw (&self) -> Result<(sys::sockaddr_un, i32), Error> {
+        let mut raw = sys::sockaddr_un::new();
+
+        let len = match *self {
+            UnixAddr::Path(ref path) => {
+                let bytes = match path.to_str() {
+                    Some(path) => path.as_bytes(),
+                    None => return Err(invalid_path()),
+                };
+
+                // Leave room for the terminator
+                if bytes.is_empty() || bytes.len() >= sys::UNIX_PATH_MAX || bytes.contains(&0) {
+                    return Err(invalid_path());
+                }
+                raw.sun_path[..bytes.len()].copy_from_slice(bytes);
+                bytes.len() + 1
+            },
+            UnixAddr::Abstract(ref name) => {
+                if name.len() >= sys::UNIX_PATH_MAX {
+                    return Err(invalid_path());
+                }
+                raw.sun_path[1..name.len() + 1].copy_from_slice(name);
+                name.len() + 1
+            },
+            UnixAddr::Unnamed => return Err(invalid_path()),
+        };
+
+        Ok((raw, (PATH_OFFSET + len) as i32))
+    }
+
+    //=======================================================================
+    // len is the address length reported by the OS, or the size of the
+    // buffer when the OS does not report it
+    fn from_raw (raw: &sys::sockaddr_un, len: i32) -> UnixAddr {
+        let len = (len as usize).saturating_sub(PATH_OFFSET);
+        let path = &raw.sun_path[..len.min(sys::UNIX_PATH_MAX)];
+
+        if path.iter().all(|&b| b == 0) {
+            return UnixAddr::Unnamed;
+        }
+        if path[0] == 0 {
+            return UnixAddr::Abstract(path[1..].to_vec());
+        }
+
+        let end = path.iter().position(|&b| b == 0).unwrap_or(path.len());
+        UnixAddr::Path(PathBuf::from(String::from_utf8_lossy(&path[..end]).into_owned()))
+    }
+
+    //=======================================================================
+    fn from_storage (storage: &sys::sockaddr_storage, len: i32) -> UnixAddr {
+        let raw: &sys::sockaddr_un = unsafe { mem::transmute(storage) };
+        UnixAddr::from_raw(raw, len)
+    }
+}
+
+
+/****************************************************************************
+*
+*   UnixListener
+*
+***/
+
+#[derive(Debug, Clone)]
+pub struct UnixListener {
+    inner: Arc<UnixListenerInner>,
+}
+
+impl UnixListener {
+    pub fn addr (&self) -> UnixAddr { self.inner.addr.clone() }
+
+    //=======================================================================
+    pub fn bind (addr: &UnixAddr, queue: queue::Queue)
+        -> Result<UnixListener, Error>
+    {
+        let socket = match listen(addr) {
+            Ok(socket) => socket,
+            Err(error) => return Err(error),
+        };
+
+        // Associate with queue
+        match queue::associate(&queue, socket.handle()) {
+            Ok(..) => {
+                Ok(UnixListener {
+                    inner: Arc::new(UnixListenerInner {
+                        queue: queue,
+                        socket: socket,
+                        addr: addr.clone(),
+                    })
+                })
+            },
+            Err(error) => Err(error),
+        }
+    }
+
+    //=======================================================================
+    pub fn accept (&self) -> Result<(), Error> {
+        self.inner.accept(self.clone())
+    }
+}
+
+impl AsRawSocket for UnixListener {
+    //=======================================================================
+    fn as_raw_socket (&self) -> RawSocket {
+        self.inner.socket.to_raw() as RawSocket
+    }
+}
+
+#[derive(Debug)]
+struct UnixListenerInner {
+    queue: queue::Queue,
+    socket: Socket,
+    addr: UnixAddr,
+}
+
+impl UnixListenerInner {
+    //=======================================================================
+    fn accept (&self, listener: UnixListener) -> Result<(), Error> {
+        // Create socket
+        let socket = Socket::new_unix(sys::SOCK_STREAM);
+        if let Err(error) = socket {
+            return Err(error);
+        }
+
+        // Create boxed context
+        let context = Box::new(AcceptContext {
+            queue: self.queue.clone(),
+            listener: listener,
+            socket: socket.unwrap(),
+            addrs: AddrBuffers::new(),
+        });
+
+        // Get raw values from context for passing to OS API
+        let socket = context.socket.to_raw();
+        let addrs: sys::LPVOID = unsafe { mem::transmute(&context.addrs) };
+
+        // Create boxed state and call OS API
+        let state = Box::new(queue::State::new(context));
+        self.socket.accept_ex(
+            socket,
+            addrs,
+            0,
+            mem::size_of::<AddrBuffer>() as u32,
+            state
+        )
+    }
+
+    //=======================================================================
+    // Abstract names may contain zeros, so the remote address is read with
+    // the length AcceptEx reports rather than up to a terminator
+    fn get_accept_remote (&self, addrs: &AddrBuffers) -> UnixAddr {
+        // Retrieve OS API
+        static GETACCEPTEXSOCKADDRS: sys::WsaExtFn = sys::WsaExtFn {
+            guid: sys::WSAID_GETACCEPTEXSOCKADDRS,
+            value: ATOMIC_USIZE_INIT,
+        };
+        let ptr = GETACCEPTEXSOCKADDRS.get(self.socket.to_raw());
+        let get_addrs: sys::FN_GETACCEPTEXSOCKADDRS = unsafe { mem::transmute(ptr) };
+
+        // Call OS API
+        let mut local: sys::PVOID = ptr::null_mut();
+        let mut local_len: i32 = 0;
+        let mut remote: sys::PVOID = ptr::null_mut();
+        let mut remote_len: i32 = 0;
+        get_addrs(
+            addrs as *const _ as sys::PVOID,
+            0,
+            mem::size_of::<AddrBuffer>() as u32,
+            mem::size_of::<AddrBuffer>() as u32,
+            &mut local,
+            &mut local_len,
+            &mut remote,
+            &mut remote_len
+        );
+
+        // The pointer is into addrs, each of which holds a full sockaddr_un
+        let remote = unsafe { &*(remote as *const sys::sockaddr_un) };
+        UnixAddr::from_raw(remote, remote_len)
+    }
+}
+
+
+/****************************************************************************
+*
+*   UCred
+*
+***/
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct UCred {
+    pub pid: u32,
+}
+
+
+/****************************************************************************
+*
+*   UnixStream
+*
+***/
+
+#[derive(Debug, Clone)]
+pub struct UnixStream {
+    inner: Arc<UnixStreamInner>,
+}
+
+impl UnixStream {
+    pub fn addr_local (&self) -> UnixAddr { self.inner.local.get() }
+    pub fn addr_remote (&self) -> UnixAddr { self.inner.remote.clone() }
+
+    //=======================================================================
+    // ConnectEx requires a bound socket, so the stream is first bound to an
+    // unnamed address. Completes with Event::UnixConnect.
+    pub fn connect (addr: &UnixAddr, queue: queue::Queue) -> Result<(), Error> {
+        let (raw, len) = match addr.to_raw() {
+            Ok(raw) => raw,
+            Err(error) => return Err(error),
+        };
+
+        let socket = match Socket::new_unix(sys::SOCK_STREAM) {
+            Ok(socket) => socket,
+            Err(error) => return Err(error),
+        };
+        let unnamed = sys::sockaddr_un::new();
+        if let Err(error) = socket.bind_raw(&unnamed as *const _ as sys::PVOID, PATH_OFFSET as i32) {
+            return Err(error);
+        }
+
+        let stream = match UnixStream::from_socket(queue, socket, UnixAddr::Unnamed, addr.clone()) {
+            Ok(stream) => stream,
+            Err(error) => return Err(error),
+        };
+
+        let state = Box::new(queue::State::new(Box::new(ConnectContext {
+            stream: stream.clone(),
+        })));
+        stream.inner.socket.connect_raw_ex(
+            &raw as *const _ as sys::PVOID,
+            len,
+            ptr::null_mut(),
+            0,
+            state
+        )
+    }
+
+    //=======================================================================
+    // Creates a pair of connected streams. Windows has no socketpair, so the
+    // pair is connected through a temporary path that is removed afterwards.
+    pub fn pair (queue: queue::Queue) -> Result<(UnixStream, UnixStream), Error> {
+        let addr = UnixAddr::Path(temp_path());
+        let result = UnixStream::pair_through(&addr, queue);
+
+        let _ = fs::remove_file(addr.as_path().unwrap());
+        result
+    }
+
+    //=======================================================================
+    fn pair_through (addr: &UnixAddr, queue: queue::Queue)
+        -> Result<(UnixStream, UnixStream), Error>
+    {
+        let listener = match listen(addr) {
+            Ok(listener) => listener,
+            Err(error) => return Err(error),
+        };
+        let (raw, len) = addr.to_raw().unwrap();
+
+        // Connect completes once queued in the backlog, so accepting after
+        // it cannot block
+        let client = match Socket::new_unix(sys::SOCK_STREAM) {
+            Ok(client) => client,
+            Err(error) => return Err(error),
+        };
+        if let Err(error) = client.connect_raw(&raw as *const _ as sys::PVOID, len) {
+            return Err(error);
+        }
+        let server = match listener.accept() {
+            Ok(server) => server,
+            Err(error) => return Err(error),
+        };
+
+        let client_local = local_addr(&client);
+        let client = UnixStream::from_socket(queue.clone(), client, client_local.clone(), addr.clone());
+        let server = UnixStream::from_socket(queue, server, addr.clone(), client_local);
+        match (client, server) {
+            (Ok(client), Ok(server)) => Ok((client, server)),
+            (Err(error), _) | (_, Err(error)) => Err(error),
+        }
+    }
+
+    //=======================================================================
+    fn from_socket (
+        queue: queue::Queue,
+        socket: Socket,
+        local: UnixAddr,
+        remote: UnixAddr
+    ) -> Result<UnixStream, Error> {
+        // Associate with queue
+        if let Err(error) = queue::associate(&queue, socket.handle()) {
+            return Err(error);
+        }
+
+        Ok(UnixStream {
+            inner: Arc::new(UnixStreamInner {
+                socket: socket,
+                local: AddrCell::new(local),
+                remote: remote,
+            }),
+        })
+    }
+
+    //=======================================================================
+    pub fn receive (&self, mut buffer: Box<[u8]>) -> Result<(), Error> {
+        let buf = sys::WSABUF::new(&mut buffer[..]);
+
+        let state = Box::new(queue::State::new(Box::new(ReceiveContext {
+            stream: self.clone(),
+            buffer: buffer,
+        })));
+
+        self.inner.socket.receive_ex(buf, state)
+    }
+
+    //=======================================================================
+    pub fn send (&self, mut buffer: Box<[u8]>) -> Result<(), Error> {
+        let buf = sys::WSABUF::new(&mut buffer[..]);
+
+        let state = Box::new(queue::State::new(Box::new(SendContext {
+            stream: self.clone(),
+            buffer: buffer,
+        })));
+
+        self.inner.socket.send_ex(buf, state)
+    }
+
+    //=======================================================================
+    // Sends buffer along with sockets, which the peer receives with
+    // recv_with_fds. Windows has no SCM_RIGHTS, so each socket is
+    // duplicated for the peer process and sent in-band. Completes with
+    // Event::UnixSend.
+    //
+    // The peer's copy shares the underlying socket, including any completion
+    // port association, which recv_with_fds removes so that the peer can
+    // adopt it into its own queue. Sending hands the socket over: once the
+    // peer has received it, operations submitted through the sender's copy,
+    // such as the TcpStream it came from, no longer complete on the
+    // sender's queue.
+    pub fn send_with_fds (&self, buffer: Box<[u8]>, sockets: &[RawSocket])
+        -> Result<(), Error>
+    {
+        if sockets.len() > MAX_FDS || buffer.len() > MAX_FD_DATA_BYTES {
+            return Err(Error::new(ErrorKind::InvalidInput, "Too many sockets or too much data"));
+        }
+
+        let pid = match self.inner.socket.peer_pid() {
+            Ok(pid) => pid,
+            Err(error) => return Err(error),
+        };
+
+        // Frame: socket count, data length, protocol infos, data
+        let mut frame = Vec::with_capacity(
+            FD_HEADER_BYTES + sockets.len() * INFO_BYTES + buffer.len()
+        );
+        push_u32(&mut frame, sockets.len() as u32);
+        push_u32(&mut frame, buffer.len() as u32);
+        for socket in sockets {
+            let info = match duplicate(*socket as sys::SOCKET, pid) {
+                Ok(info) => info,
+                Err(error) => return Err(error),
+            };
+            let bytes = unsafe {
+                slice::from_raw_parts(&info as *const _ as *const u8, INFO_BYTES)
+            };
+            frame.extend_from_slice(bytes);
+        }
+        frame.extend_from_slice(&buffer);
+
+        let mut frame = frame.into_boxed_slice();
+        let buf = sys::WSABUF::new(&mut frame[..]);
+        let state = Box::new(queue::State::new(Box::new(SendWithFdsContext {
+            stream: self.clone(),
+            buffer: buffer,
+            frame: frame,
+        })));
+
+        self.inner.socket.send_ex(buf, state)
+    }
+
+    //=======================================================================
+    // Receives data and sockets sent with send_with_fds. Data beyond the
+    // length of buffer is discarded. Received sockets are detached from any
+    // completion port and owned by the caller, who can adopt them with, for
+    // example, TcpStream::from_raw_socket. Completes with
+    // Event::UnixReceiveWithFds.
+    pub fn recv_with_fds (&self, buffer: Box<[u8]>) -> Result<(), Error> {
+        let context = Box::new(ReceiveWithFdsContext {
+            stream: self.clone(),
+            buffer: buffer,
+            frame: vec![0; FD_HEADER_BYTES],
+            received: 0,
+        });
+
+        match context.submit() {
+            Ok(()) => Ok(()),
+            Err((_, error)) => Err(error),
+        }
+    }
+
+    //=======================================================================
+    // Windows only reports the peer's process id
+    pub fn peer_cred (&self) -> Result<UCred, Error> {
+        match self.inner.socket.peer_pid() {
+            Ok(pid) => Ok(UCred { pid: pid }),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl AsRawSocket for UnixStream {
+    //=======================================================================
+    fn as_raw_socket (&self) -> RawSocket {
+        self.inner.socket.to_raw() as RawSocket
+    }
+}
+
+#[derive(Debug)]
+struct UnixStreamInner {
+    socket: Socket,
+    local: AddrCell<UnixAddr>,
+    remote: UnixAddr,
+}
+
+
+/****************************************************************************
+*
+*   AcceptContext
+*
+***/
+
+struct AcceptContext {
+    queue: queue::Queue,
+    listener: UnixListener,
+    socket: Socket,
+    addrs: AddrBuffers,
+}
+
+impl queue::Context for AcceptContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, _: u32) -> Option<queue::Event> {
+        let context = *self;
+        let local = context.listener.addr();
+        let remote = context.listener.inner.get_accept_remote(&context.addrs);
+        let result = UnixStream::from_socket(context.queue, context.socket, local, remote);
+
+        Some(queue::Event::UnixAccept(context.listener, result))
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        Some(queue::Event::UnixAccept(self.listener, Err(error)))
+    }
+}
+
+
+/****************************************************************************
+*
+*   ConnectContext
+*
+***/
+
+struct ConnectContext {
+    stream: UnixStream,
+}
+
+impl queue::Context for ConnectContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, _: u32) -> Option<queue::Event> {
+        // Get actual local address
+        let inner = &self.stream.inner;
+        inner.local.set(local_addr(&inner.socket));
+
+        Some(queue::Event::UnixConnect(self.stream, Ok(())))
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        Some(queue::Event::UnixConnect(self.stream, Err(error)))
+    }
+}
+
+
+/****************************************************************************
+*
+*   ReceiveContext
+*
+***/
+
+struct ReceiveContext {
+    stream: UnixStream,
+    buffer: Box<[u8]>,
+}
+
+impl queue::Context for ReceiveContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, bytes: u32) -> Option<queue::Event> {
+        Some(queue::Event::UnixReceive(
+            self.stream.clone(),
+            self.buffer,
+            Ok(bytes as usize)
+        ))
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        Some(queue::Event::UnixReceive(
+            self.stream.clone(),
+            self.buffer,
+            Err(error)
+        ))
+    }
+
+    //=======================================================================
+    fn into_buffer (self: Box<Self>) -> Option<Box<[u8]>> {
+        Some(self.buffer)
+    }
+}
+
+
+/****************************************************************************
+*
+*   SendContext
+*
+***/
+
+struct SendContext {
+    stream: UnixStream,
+    buffer: Box<[u8]>,
+}
+
+impl queue::Context for SendContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, _: u32) -> Option<queue::Event> {
+        Some(queue::Event::UnixSend(
+            self.stream.clone(),
+            self.buffer,
+            Ok(())
+        ))
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        Some(queue::Event::UnixSend(
+            self.stream.clone(),
+            self.buffer,
+            Err(error)
+        ))
+    }
+
+    //=======================================================================
+    fn into_buffer (self: Box<Self>) -> Option<Box<[u8]>> {
+        Some(self.buffer)
+    }
+}
+
+
+/****************************************************************************
+*
+*   SendWithFdsContext
+*
+***/
+
+struct SendWithFdsContext {
+    stream: UnixStream,
+    buffer: Box<[u8]>,
+    frame: Box<[u8]>,
+}
+
+impl queue::Context for SendWithFdsContext {
+    //=======================================================================
+    fn into_event (self: Box<Self>, bytes: u32) -> Option<queue::Event> {
+        let result = if bytes as usize == self.frame.len() {
+            Ok(())
+        }
+        else {
+            Err(Error::new(ErrorKind::Unknown, "Partial send"))
+        };
+
+        Some(queue::Event::UnixSend(self.stream.clone(), self.buffer, result))
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        Some(queue::Event::UnixSend(self.stream.clone(), self.buffer, Err(error)))
+    }
+
+    //=======================================================================
+    fn into_buffer (self: Box<Self>) -> Option<Box<[u8]>> {
+        Some(self.buffer)
+    }
+}
+
+
+/****************************************************************************
+*
+*   ReceiveWithFdsContext
+*
+***/
+
+// Reads a frame in two steps, the header then the rest, so that nothing
+// past the frame is consumed
+struct ReceiveWithFdsContext {
+    stream: UnixStream,
+    buffer: Box<[u8]>,
+    frame: Vec<u8>,
+    received: usize,
+}
+
+impl ReceiveWithFdsContext {
+    //=======================================================================
+    fn submit (mut self: Box<Self>) -> Result<(), (UnixStream, Error)> {
+        let stream = self.stream.clone();
+        let received = self.received;
+        let buf = sys::WSABUF::new(&mut self.frame[received..]);
+
+        let state = Box::new(queue::State::new(self));
+        let result = stream.inner.socket.receive_ex(buf, state);
+        match result {
+            Ok(()) => Ok(()),
+            Err(error) => Err((stream, error)),
+        }
+    }
+
+    //=======================================================================
+    fn complete (self, result: Result<(usize, Vec<RawSocket>), Error>) -> Option<queue::Event> {
+        Some(queue::Event::UnixReceiveWithFds(self.stream, self.buffer, result))
+    }
+
+    //=======================================================================
+    fn parse (&mut self) -> Result<(usize, Vec<RawSocket>), Error> {
+        let count = read_u32(&self.frame[0..4]) as usize;
+        let data_len = read_u32(&self.frame[4..8]) as usize;
+
+        // Open every socket before handing any out, so that a failure
+        // part way closes the ones already opened
+        let mut sockets = Vec::with_capacity(count);
+        for i in 0..count {
+            let offset = FD_HEADER_BYTES + i * INFO_BYTES;
+            let mut info = sys::WSAPROTOCOL_INFOW::new();
+            let bytes = unsafe {
+                slice::from_raw_parts_mut(&mut info as *mut _ as *mut u8, INFO_BYTES)
+            };
+            bytes.copy_from_slice(&self.frame[offset..offset + INFO_BYTES]);
+
+            let socket = match Socket::from_protocol_info(&info) {
+                Ok(socket) => socket,
+                Err(error) => return Err(error),
+            };
+            if let Err(error) = socket.detach_completion_port() {
+                return Err(error);
+            }
+            sockets.push(socket);
+        }
+
+        let data = &self.frame[FD_HEADER_BYTES + count * INFO_BYTES..];
+        let bytes = cmp::min(data_len, self.buffer.len());
+        self.buffer[..bytes].copy_from_slice(&data[..bytes]);
+
+        let sockets = sockets.into_iter().map(|s| s.into_raw() as RawSocket).collect();
+        Ok((bytes, sockets))
+    }
+}
+
+impl queue::Context for ReceiveWithFdsContext {
+    //=======================================================================
+    fn into_event (mut self: Box<Self>, bytes: u32) -> Option<queue::Event> {
+        // Closed between frames
+        if bytes == 0 && self.received == 0 {
+            return self.complete(Ok((0, Vec::new())));
+        }
+        if bytes == 0 {
+            let error = Error::new(ErrorKind::InvalidData, "Connection closed within a frame");
+            return self.complete(Err(error));
+        }
+
+        self.received += bytes as usize;
+
+        // Size the frame once the header is in
+        if self.received == FD_HEADER_BYTES && self.frame.len() == FD_HEADER_BYTES {
+            let count = read_u32(&self.frame[0..4]) as usize;
+            let data_len = read_u32(&self.frame[4..8]) as usize;
+            if count > MAX_FDS || data_len > MAX_FD_DATA_BYTES {
+                let error = Error::new(ErrorKind::InvalidData, "Invalid frame header");
+                return self.complete(Err(error));
+            }
+            self.frame.resize(FD_HEADER_BYTES + count * INFO_BYTES + data_len, 0);
+        }
+
+        if self.received < self.frame.len() {
+            return match self.submit() {
+                Ok(()) => None,
+                Err((stream, mut error)) => {
+                    let buffer = error.take_buffer().unwrap();
+                    Some(queue::Event::UnixReceiveWithFds(stream, buffer, Err(error)))
+                },
+            };
+        }
+
+        let result = self.parse();
+        self.complete(result)
+    }
+
+    //=======================================================================
+    fn into_error (self: Box<Self>, _: u32, error: Error) -> Option<queue::Event> {
+        self.complete(Err(error))
+    }
+
+    //=======================================================================
+    fn into_buffer (self: Box<Self>) -> Option<Box<[u8]>> {
+        Some(self.buffer)
+    }
+}
+
+
+/****************************************************************************
+*
+*   AddrBuffer
+*
+***/
+
+#[repr(C)]
+struct AddrBuffer {
+    addr: sys::sockaddr_un,
+    extra: [u8; sys::SOCKADDR_STORAGE_EXTRA_BYTES],
+}
+
+impl AddrBuffer {
+    //=======================================================================
+    fn new () -> AddrBuffer {
+        AddrBuffer {
+            addr: sys::sockaddr_un::new(),
+            extra: [0; sys::SOCKADDR_STORAGE_EXTRA_BYTES],
+        }
+    }
+}
+
+#[repr(C)]
+struct AddrBuffers {
+    local: AddrBuffer,
+    remote: AddrBuffer,
+}
+
+impl AddrBuffers {
+    //=======================================================================
+    fn new () -> AddrBuffers {
+        AddrBuffers {
+            local: AddrBuffer::new(),
+            remote: AddrBuffer::new(),
+        }
+    }
+}
+
+
+/****************************************************************************
+*
+*   Private functions
+*
+***/
+
+//===========================================================================
+fn invalid_path () -> Error {
+    Error::new(ErrorKind::InvalidInput, "Invalid Unix socket address")
+}
+
+//===========================================================================
+fn duplicate (socket: sys::SOCKET, pid: u32) -> Result<sys::WSAPROTOCOL_INFOW, Error> {
+    let mut info = sys::WSAPROTOCOL_INFOW::new();
+    let success = unsafe {
+        sys::WSADuplicateSocketW(socket, pid, &mut info) == 0
+    };
+
+    if success {
+        Ok(info)
+    }
+    else {
+        Err(Socket::last_error())
+    }
+}
+
+//===========================================================================
+fn push_u32 (buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+}
+
+//===========================================================================
+fn read_u32 (bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+//===========================================================================
+fn listen (addr: &UnixAddr) -> Result<Socket, Error> {
+    let (raw, len) = match addr.to_raw() {
+        Ok(raw) => raw,
+        Err(error) => return Err(error),
+    };
+
+    let socket = match Socket::new_unix(sys::SOCK_STREAM) {
+        Ok(socket) => socket,
+        Err(error) => return Err(error),
+    };
+    if let Err(error) = socket.bind_raw(&raw as *const _ as sys::PVOID, len) {
+        return Err(error);
+    }
+    if let Err(error) = socket.listen() {
+        return Err(error);
+    }
+
+    Ok(socket)
+}
+
+//===========================================================================
+fn local_addr (socket: &Socket) -> UnixAddr {
+    match socket.get_raw_addr() {
+        Ok((storage, len)) => UnixAddr::from_storage(&storage, len),
+        Err(..) => UnixAddr::Unnamed,
+    }
+}
+
+//===========================================================================
+fn temp_path () -> PathBuf {
+    static COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    let name = format!(
+        "ioq-{}-{}.sock",
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    );
+    env::temp_dir().join(name)
+}
+
+
+/****************************************************************************
+*
+*   Tests
+*
+***/
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::iter;
+    use std::net as std_net;
+    use std::os::windows::io::AsRawSocket;
+    use std::process;
+
+    use sys;
+    use error::ErrorKind;
+    use net;
+    use queue::{Event, Queue};
+    use super::*;
+    use super::temp_path;
+
+    //=======================================================================
+    fn round_trip (addr: &UnixAddr) -> UnixAddr {
+        let (raw, len) = addr.to_raw().unwrap();
+        UnixAddr::from_raw(&raw, len)
+    }
+
+    //=======================================================================
+    #[test]
+    fn addr_conversion () {
+        let path = UnixAddr::new("C:\\temp\\ioq.sock");
+        assert_eq!(round_trip(&path), path);
+        assert_eq!(path.to_raw().unwrap().1 as usize, 2 + 16 + 1);
+
+        let name = UnixAddr::new_abstract(b"ioq\0test");
+        assert_eq!(round_trip(&name), name);
+        assert_eq!(name.to_raw().unwrap().1 as usize, 2 + 1 + 8);
+
+        // Unnamed addresses are reported with just the family
+        let raw = sys::sockaddr_un::new();
+        assert!(UnixAddr::from_raw(&raw, 2).is_unnamed());
+    }
+
+    //=======================================================================
+    #[test]
+    fn addr_invalid () {
+        let long: String = iter::repeat('a').take(sys::UNIX_PATH_MAX).collect();
+        let invalid = [
+            UnixAddr::Unnamed,
+            UnixAddr::new(""),
+            UnixAddr::new(long),
+            UnixAddr::new("nul\0byte"),
+            UnixAddr::new_abstract(&[1u8; sys::UNIX_PATH_MAX]),
+        ];
+
+        for addr in invalid.iter() {
+            let error = addr.to_raw().err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn accept_connect () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let path = temp_path();
+        let addr = UnixAddr::new(&path);
+        let listener = UnixListener::bind(&addr, queue.clone()).unwrap();
+        listener.accept().unwrap();
+        UnixStream::connect(&addr, queue.clone()).unwrap();
+
+        let mut server = None;
+        let mut client = None;
+        while server.is_none() || client.is_none() {
+            match queue.dequeue().unwrap() {
+                Event::UnixAccept(_, result) => server = Some(result.unwrap()),
+                Event::UnixConnect(stream, result) => {
+                    result.unwrap();
+                    client = Some(stream);
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+
+        let server = server.unwrap();
+        let client = client.unwrap();
+        assert_eq!(server.addr_local(), addr);
+        assert_eq!(client.addr_remote(), addr);
+
+        server.receive(Box::new([0u8; 16])).unwrap();
+        client.send(Box::new([1u8, 2, 3])).unwrap();
+        let mut received = false;
+        while !received {
+            match queue.dequeue().unwrap() {
+                Event::UnixSend(_, _, result) => result.unwrap(),
+                Event::UnixReceive(_, buffer, result) => {
+                    assert_eq!(&buffer[..result.unwrap()], &[1, 2, 3]);
+                    received = true;
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+
+        drop(listener);
+        fs::remove_file(path).unwrap();
+    }
+
+    //=======================================================================
+    #[test]
+    fn accept_connect_abstract () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let name = format!("ioq-abstract-{}", process::id());
+        let addr = UnixAddr::new_abstract(name.as_bytes());
+        let listener = UnixListener::bind(&addr, queue.clone()).unwrap();
+        assert_eq!(listener.addr(), addr);
+        listener.accept().unwrap();
+        UnixStream::connect(&addr, queue.clone()).unwrap();
+
+        let mut server = None;
+        let mut client = None;
+        while server.is_none() || client.is_none() {
+            match queue.dequeue().unwrap() {
+                Event::UnixAccept(_, result) => server = Some(result.unwrap()),
+                Event::UnixConnect(stream, result) => {
+                    result.unwrap();
+                    client = Some(stream);
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+
+        // Nothing is created in the filesystem
+        let server = server.unwrap();
+        let client = client.unwrap();
+        assert_eq!(server.addr_local(), addr);
+        assert_eq!(client.addr_remote(), addr);
+        assert!(!Path::new(&name).exists());
+
+        client.receive(Box::new([0u8; 16])).unwrap();
+        server.send(Box::new([4u8, 5, 6])).unwrap();
+        let mut received = false;
+        while !received {
+            match queue.dequeue().unwrap() {
+                Event::UnixSend(_, _, result) => result.unwrap(),
+                Event::UnixReceive(_, buffer, result) => {
+                    assert_eq!(&buffer[..result.unwrap()], &[4, 5, 6]);
+                    received = true;
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn connect_missing () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        // Depending on the error, ConnectEx fails when submitting or when
+        // completing
+        let addr = UnixAddr::new(temp_path());
+        if UnixStream::connect(&addr, queue.clone()).is_ok() {
+            match queue.dequeue().unwrap() {
+                Event::UnixConnect(_, result) => assert!(result.is_err()),
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn stream_pair () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let (a, b) = UnixStream::pair(queue.clone()).unwrap();
+        assert!(!a.addr_remote().as_path().unwrap().exists());
+
+        b.receive(Box::new([0u8; 16])).unwrap();
+        a.send(Box::new([4u8, 5])).unwrap();
+
+        let mut received = false;
+        while !received {
+            match queue.dequeue().unwrap() {
+                Event::UnixSend(_, _, result) => result.unwrap(),
+                Event::UnixReceive(_, buffer, result) => {
+                    assert_eq!(&buffer[..result.unwrap()], &[4, 5]);
+                    received = true;
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+
+        // Closing one end shows up as a zero byte receive on the other
+        drop(a);
+        b.receive(Box::new([0u8; 16])).unwrap();
+        match queue.dequeue().unwrap() {
+            Event::UnixReceive(_, _, result) => assert_eq!(result.unwrap(), 0),
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    //=======================================================================
+    #[test]
+    fn peer_cred () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let (a, b) = UnixStream::pair(queue).unwrap();
+        assert_eq!(a.peer_cred().unwrap().pid, process::id());
+        assert_eq!(b.peer_cred().unwrap().pid, process::id());
+    }
+
+    //=======================================================================
+    #[test]
+    fn pass_socket () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        // A std socket has never been associated with a completion port
+        let listener = std_net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let connected = std_net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+
+        let (a, b) = UnixStream::pair(queue.clone()).unwrap();
+        b.recv_with_fds(Box::new([0u8; 16])).unwrap();
+        a.send_with_fds(Box::new([7u8, 8, 9]), &[connected.as_raw_socket()]).unwrap();
+        drop(connected);
+
+        let mut stream = None;
+        while stream.is_none() {
+            match queue.dequeue().unwrap() {
+                Event::UnixSend(_, buffer, result) => {
+                    result.unwrap();
+                    assert_eq!(&buffer[..], &[7, 8, 9]);
+                },
+                Event::UnixReceiveWithFds(_, buffer, result) => {
+                    let (bytes, sockets) = result.unwrap();
+                    assert_eq!(&buffer[..bytes], &[7, 8, 9]);
+                    assert_eq!(sockets.len(), 1);
+                    stream = Some(unsafe { net::TcpStream::from_raw_socket(sockets[0], queue.clone()) }.unwrap());
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+
+        // The duplicated socket is still connected to the peer
+        stream.unwrap().send(Box::new([1u8, 2])).unwrap();
+        match queue.dequeue().unwrap() {
+            Event::TcpSend(_, _, result) => result.unwrap(),
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        let mut received = [0u8; 2];
+        peer.read_exact(&mut received).unwrap();
+        assert_eq!(received, [1, 2]);
+    }
+
+    //=======================================================================
+    #[test]
+    fn pass_associated_socket () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let listener = std_net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let connected = std_net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let connected = net::TcpStream::from_std(connected, queue.clone()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+
+        let (a, b) = UnixStream::pair(queue.clone()).unwrap();
+        b.recv_with_fds(Box::new([0u8; 16])).unwrap();
+        a.send_with_fds(Box::new([]), &[connected.as_raw_socket()]).unwrap();
+
+        let mut sockets = None;
+        while sockets.is_none() {
+            match queue.dequeue().unwrap() {
+                Event::UnixSend(_, _, result) => { result.unwrap(); },
+                Event::UnixReceiveWithFds(_, _, result) => sockets = Some(result.unwrap().1),
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+        drop(connected);
+
+        // The duplicate was associated with queue through the original, and
+        // is adopted into another queue where its operations complete
+        let other = Queue::new().unwrap();
+        let sockets = sockets.unwrap();
+        assert_eq!(sockets.len(), 1);
+        let stream = unsafe { net::TcpStream::from_raw_socket(sockets[0], other.clone()) }.unwrap();
+
+        stream.receive(Box::new([0u8; 16])).unwrap();
+        peer.write_all(&[3, 4]).unwrap();
+        match other.dequeue().unwrap() {
+            Event::TcpReceive(_, buffer, result) => assert_eq!(&buffer[..result.unwrap()], &[3, 4]),
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        stream.send(Box::new([1u8, 2])).unwrap();
+        match other.dequeue().unwrap() {
+            Event::TcpSend(_, _, result) => result.unwrap(),
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        let mut received = [0u8; 2];
+        peer.read_exact(&mut received).unwrap();
+        assert_eq!(received, [1, 2]);
+    }
+
+    //=======================================================================
+    #[test]
+    fn accept_abstract_peer () {
+        let _init = net::initialize().unwrap();
+        let queue = Queue::new().unwrap();
+
+        let addr = UnixAddr::new_abstract(format!("ioq-listen-{}", process::id()).as_bytes());
+        let listener = UnixListener::bind(&addr, queue.clone()).unwrap();
+        listener.accept().unwrap();
+
+        // The peer's name ends in a zero, which only the reported length
+        // keeps
+        let name = UnixAddr::new_abstract(format!("ioq-peer-{}\0", process::id()).as_bytes());
+        let (raw, len) = name.to_raw().unwrap();
+        let client = Socket::new_unix(sys::SOCK_STREAM).unwrap();
+        client.bind_raw(&raw as *const _ as sys::PVOID, len).unwrap();
+        let (raw, len) = addr.to_raw().unwrap();
+        client.connect_raw(&raw as *const _ as sys::PVOID, len).unwrap();
+
+        match queue.dequeue().unwrap() {
+            Event::UnixAccept(_, result) => assert_eq!(result.unwrap().addr_remote(), name),
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+}
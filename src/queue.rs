@@ -7,12 +7,18 @@
 *
 ***/
 
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::mem;
+use std::os::windows::io::RawSocket;
 use std::ptr;
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use error::Error;
-use handle::Handle;
+use error::{self, Error};
+use handle::{HandleRef, OwnedHandle};
 use net;
 use sys;
 
@@ -23,9 +29,14 @@ use sys;
 *
 ***/
 
+// Contexts return None for completions that are handled internally and
+// should not be reported by dequeue
 pub trait Context {
-    fn into_event (self: Box<Self>, bytes: u32) -> Event;
-    fn into_error (self: Box<Self>, bytes: u32) -> Event;
+    fn into_event (self: Box<Self>, bytes: u32) -> Option<Event>;
+    fn into_error (self: Box<Self>, bytes: u32, error: Error) -> Option<Event>;
+
+    // Returns the caller's buffer when the operation could not be submitted
+    fn into_buffer (self: Box<Self>) -> Option<Box<[u8]>> { None }
 }
 
 pub trait Custom {
@@ -43,9 +54,22 @@ pub trait Custom {
 pub enum Event {
     Custom,
     TcpAccept(net::TcpListener, Result<net::TcpStream, Error>),
+    TcpAcceptWithData(net::TcpListener, Result<net::TcpStream, Error>, Box<[u8]>, usize),
     TcpReceive(net::TcpStream, Box<[u8]>, Result<usize, Error>),
     TcpSend(net::TcpStream, Box<[u8]>, Result<(), Error>),
     TcpConnect(net::TcpStream, Result<(), Error>),
+    TcpConnectWithData(net::TcpStream, Box<[u8]>, Result<usize, Error>),
+    UdpReceive(net::UdpSocket, Box<[u8]>, Result<(usize, net::SocketAddr), Error>),
+    UdpSend(net::UdpSocket, Box<[u8]>, Result<usize, Error>),
+    UdpReceiveBatch(net::UdpSocket, Vec<Box<[u8]>>, Vec<Result<(usize, net::SocketAddr), Error>>),
+    UdpSendBatch(net::UdpSocket, Vec<(Box<[u8]>, net::SocketAddr)>, Vec<Result<usize, Error>>),
+    UnixAccept(net::UnixListener, Result<net::UnixStream, Error>),
+    UnixConnect(net::UnixStream, Result<(), Error>),
+    UnixReceive(net::UnixStream, Box<[u8]>, Result<usize, Error>),
+    UnixSend(net::UnixStream, Box<[u8]>, Result<(), Error>),
+    UnixReceiveWithFds(net::UnixStream, Box<[u8]>, Result<(usize, Vec<RawSocket>), Error>),
+    Resolved(String, u16, Result<Vec<net::SocketAddr>, Error>),
+    DnsAnswer(net::dns::Question, Result<Vec<net::dns::Record>, Error>),
 }
 
 
@@ -59,6 +83,10 @@ pub enum Event {
 pub struct State {
     overlapped: sys::OVERLAPPED,
     context: Box<Context>,
+    deadline: u64,
+    deadline_queue: Option<Arc<QueueInner>>,
+    timed_out: AtomicBool,
+    socket: Option<sys::SOCKET>,
 }
 
 impl State {
@@ -69,6 +97,47 @@ impl State {
         State {
             overlapped: sys::OVERLAPPED::new(),
             context: unsafe { Box::from_raw(raw) },
+            deadline: 0,
+            deadline_queue: None,
+            timed_out: AtomicBool::new(false),
+            socket: None,
+        }
+    }
+
+    //=======================================================================
+    // Records the socket the operation is submitted on, so that a failure
+    // can be reported with the operation's own Winsock status
+    pub fn set_socket (&mut self, socket: sys::SOCKET) {
+        self.socket = Some(socket);
+    }
+
+    //=======================================================================
+    // The completion port reports failures as system codes translated from
+    // the operation's status (a reset connection becomes
+    // ERROR_NETNAME_DELETED), so ask Winsock for the original code
+    fn error (&self, code: i32) -> Error {
+        let socket = match self.socket {
+            Some(socket) => socket,
+            None => return Error::from_os_error_code(code),
+        };
+
+        let mut bytes: u32 = 0;
+        let mut flags: u32 = 0;
+        let success = unsafe {
+            sys::WSAGetOverlappedResult(
+                socket,
+                self.overlapped_raw(),
+                &mut bytes,
+                0,
+                &mut flags
+            ) != 0
+        };
+
+        if success {
+            Error::from_os_error_code(code)
+        }
+        else {
+            Error::from_os_error_code(unsafe { sys::WSAGetLastError() })
         }
     }
 
@@ -123,7 +192,10 @@ impl Queue {
         else {
             Ok(Queue {
                 inner: Arc::new(QueueInner {
-                    handle: Handle::from_raw(raw),
+                    handle: unsafe { OwnedHandle::from_raw(raw) },
+                    deadlines: Mutex::new(Deadlines::new()),
+                    deadline_count: AtomicUsize::new(0),
+                    cancelled: Condvar::new(),
                 })
             })
         }
@@ -140,17 +212,43 @@ impl Queue {
     }
 }
 
+// Refers to a queue without keeping it alive, for state that the queue may
+// itself end up holding, such as a scheduled context
+#[derive(Debug, Clone)]
+pub struct WeakQueue {
+    inner: Weak<QueueInner>,
+}
+
+impl WeakQueue {
+    //=======================================================================
+    pub fn new (queue: &Queue) -> WeakQueue {
+        WeakQueue {
+            inner: Arc::downgrade(&queue.inner),
+        }
+    }
+
+    //=======================================================================
+    pub fn upgrade (&self) -> Option<Queue> {
+        self.inner.upgrade().map(|inner| Queue { inner: inner })
+    }
+}
+
 #[derive(Debug)]
 struct QueueInner {
-    handle: Handle,
+    handle: OwnedHandle,
+    deadlines: Mutex<Deadlines>,
+    deadline_count: AtomicUsize,
+    cancelled: Condvar,
 }
 
 impl QueueInner {
     //=======================================================================
     fn enqueue (&self, custom: Box<Custom>) -> Result<(), Error> {
-        // Create state
-        let context = Box::new(CustomContext::new(custom));
-        let state = Box::new(State::new(context));
+        self.post(Box::new(State::new(Box::new(CustomContext::new(custom)))))
+    }
+
+    //=======================================================================
+    fn post (&self, state: Box<State>) -> Result<(), Error> {
         let overlapped = state.overlapped_raw();
 
         // Post event
@@ -175,43 +273,294 @@ impl QueueInner {
 
     //=======================================================================
     fn dequeue (&self) -> Result<Event, Error> {
-        // Output data
-        let mut bytes: u32 = 0;
-        let mut key: sys::ULONG_PTR = ptr::null_mut();
-        let mut overlapped: *mut sys::OVERLAPPED = ptr::null_mut();
-
-        // Get completion data
-        let success = unsafe {
-            sys::GetQueuedCompletionStatus(
-                self.handle.to_raw(),
-                &mut bytes as *mut u32,
-                &mut key as *mut sys::ULONG_PTR,
-                &mut overlapped as *mut *mut sys::OVERLAPPED,
-                sys::INFINITE
-            ) != 0
-        };
+        loop {
+            // Cancel operations whose deadline has passed
+            self.expire_deadlines();
+
+            // Output data
+            let mut bytes: u32 = 0;
+            let mut key: sys::ULONG_PTR = ptr::null_mut();
+            let mut overlapped: *mut sys::OVERLAPPED = ptr::null_mut();
+
+            // Get completion data
+            let success = unsafe {
+                sys::GetQueuedCompletionStatus(
+                    self.handle.to_raw(),
+                    &mut bytes as *mut u32,
+                    &mut key as *mut sys::ULONG_PTR,
+                    &mut overlapped as *mut *mut sys::OVERLAPPED,
+                    self.wait_milliseconds()
+                ) != 0
+            };
+            // Read the status before anything else can overwrite it
+            let code = if success { None } else { Some(error::last_error_code()) };
+
+            // No completion: either a wake up to recompute the wait time,
+            // a wait that ended because a deadline passed, or a failure
+            if overlapped.is_null() {
+                if success && key == WAKE_KEY {
+                    continue;
+                }
+
+                match code {
+                    Some(sys::WAIT_TIMEOUT) => continue,
+                    Some(code) => return Err(Error::from_os_error_code(code)),
+                    None => return Err(Error::unknown()),
+                }
+            }
 
-        if success || overlapped != ptr::null_mut() {
             let state = unsafe { State::from_overlapped_raw(overlapped) };
+            self.clear_deadline(&state);
+            let error = code.map(|code| state.error(code));
+
+            // Only the abort caused by an expired deadline becomes a timeout;
+            // a completion that succeeded or failed on its own wins
+            let timed_out = state.timed_out.load(Ordering::SeqCst)
+                && code == Some(sys::ERROR_OPERATION_ABORTED);
             let context = state.into_context();
 
-            if success {
-                Ok(context.into_event(bytes))
+            let event = match error {
+                None => context.into_event(bytes),
+                Some(..) if timed_out => context.into_error(bytes, Error::timed_out()),
+                Some(error) => context.into_error(bytes, error),
+            };
+
+            if let Some(event) = event {
+                return Ok(event);
             }
-            else {
-                Ok(context.into_error(bytes))
+        }
+    }
+
+    //=======================================================================
+    fn set_deadline (&self, state: &mut State, handle: HandleRef, timeout: Duration) {
+        let expires = Instant::now() + timeout;
+        let action = Action::Cancel(handle.to_raw(), state.overlapped_raw(), RETRY_MS);
+
+        let earliest = {
+            let mut deadlines = self.deadlines.lock().unwrap();
+            state.deadline = deadlines.insert(expires, action);
+            self.deadline_count.fetch_add(1, Ordering::SeqCst);
+            deadlines.next() == Some(expires)
+        };
+
+        // Wake a dequeuing thread so it waits no longer than the new deadline
+        if earliest {
+            self.post_wake();
+        }
+    }
+
+    //=======================================================================
+    fn schedule (&self, delay: Duration, state: Box<State>) {
+        let expires = Instant::now() + delay;
+
+        let earliest = {
+            let mut deadlines = self.deadlines.lock().unwrap();
+            deadlines.insert(expires, Action::Post(state));
+            self.deadline_count.fetch_add(1, Ordering::SeqCst);
+            deadlines.next() == Some(expires)
+        };
+
+        // Wake a dequeuing thread so it waits no longer than the new deadline
+        if earliest {
+            self.post_wake();
+        }
+    }
+
+    //=======================================================================
+    fn clear_deadline (&self, state: &State) {
+        if state.deadline == 0 {
+            return;
+        }
+
+        // An expiring thread may be cancelling the operation without the
+        // lock; wait so the state outlives the cancel
+        let mut deadlines = self.deadlines.lock().unwrap();
+        while deadlines.cancelling.contains(&state.deadline) {
+            deadlines = self.cancelled.wait(deadlines).unwrap();
+        }
+
+        if deadlines.remove(state.deadline) {
+            self.deadline_count.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    //=======================================================================
+    fn expire_deadlines (&self) {
+        if self.deadline_count.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut deadlines = self.deadlines.lock().unwrap();
+        while let Some((id, action)) = deadlines.pop_expired(now) {
+            match action {
+                Action::Cancel(handle, overlapped, retry_ms) => {
+                    // Cancel without the lock. Marking the id as cancelling
+                    // keeps the state alive, because its completion waits in
+                    // clear_deadline until the mark is removed.
+                    deadlines.cancelling.insert(id);
+                    drop(deadlines);
+
+                    let cancelled = unsafe {
+                        sys::CancelIoEx(handle, overlapped) != 0
+                    };
+                    let not_found = !cancelled
+                        && error::last_error_code() == sys::ERROR_NOT_FOUND;
+
+                    // Marked before the completion can get past clear_deadline
+                    if cancelled {
+                        let state = overlapped as *mut State;
+                        unsafe { (*state).timed_out.store(true, Ordering::SeqCst) };
+                    }
+
+                    deadlines = self.deadlines.lock().unwrap();
+                    deadlines.cancelling.remove(&id);
+                    self.cancelled.notify_all();
+
+                    if not_found {
+                        // The operation has not been submitted yet, or has
+                        // completed but has not been dequeued; try again,
+                        // backing off so a long race does not spin
+                        let retry = Instant::now() + Duration::from_millis(retry_ms);
+                        let next_ms = cmp::min(retry_ms * 2, MAX_RETRY_MS);
+                        deadlines.reinsert(retry, id, Action::Cancel(handle, overlapped, next_ms));
+                        continue;
+                    }
+                },
+                Action::Post(state) => {
+                    let _ = self.post(state);
+                },
             }
+
+            self.deadline_count.fetch_sub(1, Ordering::SeqCst);
         }
-        else {
-            Err(Error::os_error())
+    }
+
+    //=======================================================================
+    fn wait_milliseconds (&self) -> u32 {
+        if self.deadline_count.load(Ordering::SeqCst) == 0 {
+            return sys::INFINITE;
+        }
+
+        let next = self.deadlines.lock().unwrap().next();
+        match next {
+            Some(expires) => {
+                let now = Instant::now();
+                if expires <= now {
+                    return 0;
+                }
+
+                // Round up so the wait does not end just before the deadline
+                let wait = expires - now;
+                let ms = wait.as_secs() * 1000 + (wait.subsec_nanos() as u64 + 999_999) / 1_000_000;
+                cmp::min(ms, (sys::INFINITE - 1) as u64) as u32
+            },
+            None => sys::INFINITE,
         }
     }
+
+    //=======================================================================
+    fn post_wake (&self) {
+        unsafe {
+            sys::PostQueuedCompletionStatus(
+                self.handle.to_raw(),
+                0,
+                WAKE_KEY,
+                ptr::null_mut()
+            )
+        };
+    }
 }
 
-impl Drop for QueueInner {
+/****************************************************************************
+*
+*   Deadlines
+*
+***/
+
+const WAKE_KEY: sys::ULONG_PTR = 1 as sys::ULONG_PTR;
+const RETRY_MS: u64 = 1;
+const MAX_RETRY_MS: u64 = 64;
+
+// Cancel the operation owning the OVERLAPPED, retrying after the given
+// number of milliseconds if it is not found, or post a state to the queue.
+// The entry outlives the borrow of the handle, so it holds the raw value;
+// the operation's context keeps the handle open until the deadline is
+// cleared on completion.
+enum Action {
+    Cancel(sys::HANDLE, *mut sys::OVERLAPPED, u64),
+    Post(Box<State>),
+}
+
+unsafe impl Send for Action {}
+
+impl fmt::Debug for Action {
+    fn fmt (&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Action::Cancel(ref handle, ref overlapped, ref retry_ms) =>
+                fmt.debug_tuple("Cancel").field(handle).field(overlapped).field(retry_ms).finish(),
+            Action::Post(ref state) =>
+                fmt.debug_tuple("Post").field(&state.overlapped_raw()).finish(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Deadlines {
+    next_id: u64,
+    by_time: BTreeMap<(Instant, u64), Action>,
+    by_id: HashMap<u64, Instant>,
+    cancelling: HashSet<u64>,
+}
+
+impl Deadlines {
+    //=======================================================================
+    fn new () -> Deadlines {
+        Deadlines {
+            next_id: 1,
+            by_time: BTreeMap::new(),
+            by_id: HashMap::new(),
+            cancelling: HashSet::new(),
+        }
+    }
+
+    //=======================================================================
+    fn next (&self) -> Option<Instant> {
+        self.by_time.keys().next().map(|&(expires, _)| expires)
+    }
+
+    //=======================================================================
+    fn insert (&mut self, expires: Instant, action: Action) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.reinsert(expires, id, action);
+        id
+    }
+
     //=======================================================================
-    fn drop (&mut self) {
-        let _ = self.handle.close();
+    fn reinsert (&mut self, expires: Instant, id: u64, action: Action) {
+        self.by_time.insert((expires, id), action);
+        self.by_id.insert(id, expires);
+    }
+
+    //=======================================================================
+    fn remove (&mut self, id: u64) -> bool {
+        match self.by_id.remove(&id) {
+            Some(expires) => self.by_time.remove(&(expires, id)).is_some(),
+            None => false,
+        }
+    }
+
+    //=======================================================================
+    fn pop_expired (&mut self, now: Instant) -> Option<(u64, Action)> {
+        let key = match self.by_time.keys().next() {
+            Some(&(expires, id)) if expires <= now => (expires, id),
+            _ => return None,
+        };
+
+        self.by_id.remove(&key.1);
+        self.by_time.remove(&key).map(|action| (key.1, action))
     }
 }
 
@@ -237,15 +586,15 @@ impl CustomContext {
 
 impl Context for CustomContext {
     //=======================================================================
-    fn into_event (self: Box<Self>, _: u32) -> Event {
+    fn into_event (self: Box<Self>, _: u32) -> Option<Event> {
         self.custom.execute();
-        Event::Custom
+        Some(Event::Custom)
     }
 
     //=======================================================================
-    fn into_error (self: Box<Self>, _: u32) -> Event {
+    fn into_error (self: Box<Self>, _: u32, _: Error) -> Option<Event> {
         self.custom.execute();
-        Event::Custom
+        Some(Event::Custom)
     }
 }
 
@@ -257,7 +606,7 @@ impl Context for CustomContext {
 ***/
 
 //===========================================================================
-pub fn associate (queue: &Queue, handle: Handle) -> Result<(), Error> {
+pub fn associate (queue: &Queue, handle: HandleRef) -> Result<(), Error> {
     let queue_handle_raw = queue.inner.handle.to_raw();
 
     let success = unsafe {
@@ -278,6 +627,43 @@ pub fn associate (queue: &Queue, handle: Handle) -> Result<(), Error> {
 }
 
 
+//===========================================================================
+// Cancels the operation owning state if it has not completed before timeout
+// elapses. Must be called before the operation is submitted.
+pub fn set_deadline (
+    queue: &Queue,
+    state: &mut State,
+    handle: HandleRef,
+    timeout: Duration
+) {
+    queue.inner.set_deadline(state, handle, timeout);
+    state.deadline_queue = Some(queue.inner.clone());
+}
+
+//===========================================================================
+// Releases state after submitting its operation failed, returning the
+// caller's buffer if the context holds one
+pub fn abandon (state: Box<State>) -> Option<Box<[u8]>> {
+    if let Some(ref queue) = state.deadline_queue {
+        queue.clear_deadline(&state);
+    }
+
+    state.into_context().into_buffer()
+}
+
+//===========================================================================
+// Completes context on the queue immediately
+pub fn post (queue: &Queue, context: Box<Context>) -> Result<(), Error> {
+    queue.inner.post(Box::new(State::new(context)))
+}
+
+//===========================================================================
+// Completes context on the queue once delay has elapsed
+pub fn schedule (queue: &Queue, delay: Duration, context: Box<Context>) {
+    queue.inner.schedule(delay, Box::new(State::new(context)));
+}
+
+
 /****************************************************************************
 *
 *   Tests
@@ -10,6 +10,7 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
+use std::cmp;
 use std::mem;
 use std::ptr;
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -35,11 +36,13 @@ pub type LPVOID = *mut libc::c_void;
 pub type LPCVOID = *const libc::c_void;
 pub type PVOID = *mut libc::c_void;
 pub type LPINT = *mut i32;
-pub type LPTSTR = *mut u8;
+pub type LPWSTR = *mut u16;
+pub type HLOCAL = *mut libc::c_void;
 pub type VA_LIST = *mut libc::c_char;
 pub type LPWSABUF = *mut WSABUF;
 pub type LPOVERLAPPED = *mut OVERLAPPED;
 pub type WSA_COMPL_ROUTINE = extern "C" fn (DWORD, DWORD, LPOVERLAPPED, DWORD);
+pub type LOOKUPSERVICE_COMPLETION_ROUTINE = extern "stdcall" fn (DWORD, DWORD, LPOVERLAPPED);
 
 
 /****************************************************************************
@@ -54,29 +57,116 @@ const WSASYS_STATUS_LEN: usize = 128;
 pub const INVALID_SOCKET: SOCKET = !0 as SOCKET;
 pub const SOMAXCONN: i32 = 0x7fffffff;
 
+pub const AF_UNSPEC: i32 = 0;
+pub const AF_UNIX: i32 = 1;
 pub const AF_INET: i32 = 2;
 pub const AF_INET6: i32 = 23;
 
 pub const SOCK_STREAM: i32 = 1;
+pub const SOCK_DGRAM: i32 = 2;
 
+pub const SOL_SOCKET: i32 = 0xffff;
+pub const SO_BROADCAST: i32 = 0x0020;
+pub const SO_LINGER: i32 = 0x0080;
+
+pub const IPPROTO_IP: i32 = 0;
 pub const IPPROTO_TCP: i32 = 6;
+pub const IPPROTO_UDP: i32 = 17;
+pub const IPPROTO_IPV6: i32 = 41;
+
+pub const TCP_FASTOPEN: i32 = 15;
+pub const IPV6_V6ONLY: i32 = 27;
+
+pub const IP_MULTICAST_IF: i32 = 9;
+pub const IP_MULTICAST_TTL: i32 = 10;
+pub const IP_MULTICAST_LOOP: i32 = 11;
+pub const IP_ADD_MEMBERSHIP: i32 = 12;
+pub const IP_DROP_MEMBERSHIP: i32 = 13;
+
+pub const IPV6_MULTICAST_IF: i32 = 9;
+pub const IPV6_MULTICAST_HOPS: i32 = 10;
+pub const IPV6_MULTICAST_LOOP: i32 = 11;
+pub const IPV6_ADD_MEMBERSHIP: i32 = 12;
+pub const IPV6_DROP_MEMBERSHIP: i32 = 13;
 
 pub const INVALID_HANDLE_VALUE: HANDLE = 0xFFFFFFFFFFFFFFFF as HANDLE;
 pub const NULL_HANDLE: HANDLE = 0 as HANDLE;
 
 pub const INFINITE: u32 = 0xFFFFFFFF;
 
+pub const ERROR_FILE_NOT_FOUND: i32 = 2;
+pub const ERROR_PATH_NOT_FOUND: i32 = 3;
+pub const ERROR_ACCESS_DENIED: i32 = 5;
+pub const ERROR_INVALID_HANDLE: i32 = 6;
+pub const ERROR_NOT_SUPPORTED: i32 = 50;
+pub const ERROR_NETNAME_DELETED: i32 = 64;
+pub const ERROR_FILE_EXISTS: i32 = 80;
+pub const ERROR_INVALID_PARAMETER: i32 = 87;
+pub const ERROR_BROKEN_PIPE: i32 = 109;
+pub const ERROR_CALL_NOT_IMPLEMENTED: i32 = 120;
+pub const ERROR_SEM_TIMEOUT: i32 = 121;
 pub const ERROR_INSUFFICIENT_BUFFER: i32 = 122;
+pub const ERROR_ALREADY_EXISTS: i32 = 183;
+pub const ERROR_NO_DATA: i32 = 232;
+pub const WAIT_TIMEOUT: i32 = 258;
+pub const ERROR_OPERATION_ABORTED: i32 = 995;
 pub const ERROR_IO_PENDING: i32 = 997;
-
+pub const ERROR_NOT_FOUND: i32 = 1168;
+pub const ERROR_CANCELLED: i32 = 1223;
+pub const ERROR_CONNECTION_REFUSED: i32 = 1225;
+pub const ERROR_NETWORK_UNREACHABLE: i32 = 1231;
+pub const ERROR_HOST_UNREACHABLE: i32 = 1232;
+pub const ERROR_PORT_UNREACHABLE: i32 = 1234;
+pub const ERROR_CONNECTION_ABORTED: i32 = 1236;
+pub const ERROR_TIMEOUT: i32 = 1460;
+
+pub const WSAEINTR: i32 = 10004;
+pub const WSAEACCES: i32 = 10013;
+pub const WSAEINVAL: i32 = 10022;
+pub const WSAEWOULDBLOCK: i32 = 10035;
+pub const WSAEADDRINUSE: i32 = 10048;
+pub const WSAEADDRNOTAVAIL: i32 = 10049;
+pub const WSAENETUNREACH: i32 = 10051;
+pub const WSAENETRESET: i32 = 10052;
+pub const WSAECONNABORTED: i32 = 10053;
+pub const WSAECONNRESET: i32 = 10054;
+pub const WSAENOTCONN: i32 = 10057;
+pub const WSAESHUTDOWN: i32 = 10058;
+pub const WSAETIMEDOUT: i32 = 10060;
+pub const WSAECONNREFUSED: i32 = 10061;
+pub const WSAEHOSTUNREACH: i32 = 10065;
+pub const WSAECANCELLED: i32 = 10103;
+pub const WSAHOST_NOT_FOUND: i32 = 11001;
+pub const WSANO_DATA: i32 = 11004;
+
+pub const FORMAT_MESSAGE_ALLOCATE_BUFFER: u32 = 0x00000100;
 pub const FORMAT_MESSAGE_FROM_SYSTEM: u32 = 0x00001000;
 pub const FORMAT_MESSAGE_IGNORE_INSERTS: u32 = 0x00000200;
 pub const FORMAT_MESSAGE_MAX_WIDTH_MASK: u32 = 0x000000FF;
 
 pub const SOCKADDR_STORAGE_EXTRA_BYTES: usize = 16;
 pub const SOCKADDR_MAX_BYTES: usize = 28; // Size of the longest sockaddr_* struct
+pub const UNIX_PATH_MAX: usize = 108;
 
 pub const SIO_GET_EXTENSION_FUNCTION_POINTER: DWORD = 0xc8000006;
+pub const SIO_AF_UNIX_GETPEERPID: DWORD = 0x58000100;
+pub const SIO_UDP_CONNRESET: DWORD = 0x9800000C;
+
+pub const NS_ALL: DWORD = 0;
+
+pub const ERROR_BUFFER_OVERFLOW: u32 = 111;
+pub const GAA_FLAG_SKIP_UNICAST: u32 = 0x0001;
+pub const GAA_FLAG_SKIP_ANYCAST: u32 = 0x0002;
+pub const GAA_FLAG_SKIP_MULTICAST: u32 = 0x0004;
+pub const GAA_FLAG_SKIP_FRIENDLY_NAME: u32 = 0x0020;
+pub const IF_OPER_STATUS_UP: i32 = 1;
+
+pub const FROM_PROTOCOL_INFO: i32 = -1;
+pub const WSA_FLAG_OVERLAPPED: DWORD = 0x01;
+pub const WSAPROTOCOL_LEN: usize = 255;
+pub const MAX_PROTOCOL_CHAIN: usize = 7;
+
+pub const FILE_REPLACE_COMPLETION_INFORMATION: u32 = 61;
 
 
 /****************************************************************************
@@ -120,6 +210,24 @@ pub const WSAID_CONNECTEX: GUID = GUID {
     Data4: [ 0x8e, 0xe9, 0x76, 0xe5, 0x8c, 0x74, 0x06, 0x3e ],
 };
 
+pub type FN_GETACCEPTEXSOCKADDRS = extern "C" fn (
+    PVOID,
+    DWORD,
+    DWORD,
+    DWORD,
+    *mut PVOID,
+    LPINT,
+    *mut PVOID,
+    LPINT
+);
+
+pub const WSAID_GETACCEPTEXSOCKADDRS: GUID = GUID {
+    Data1: 0xb5367df2,
+    Data2: 0xcbac,
+    Data3: 0x11cf,
+    Data4: [ 0x95, 0xca, 0x00, 0x80, 0x5f, 0x48, 0xa1, 0x92 ],
+};
+
 
 /****************************************************************************
 *
@@ -127,6 +235,7 @@ pub const WSAID_CONNECTEX: GUID = GUID {
 *
 ***/
 
+#[repr(C)]
 #[allow(dead_code)]
 pub struct GUID {
     Data1: u32,
@@ -171,6 +280,92 @@ impl WSAData {
 }
 
 
+/****************************************************************************
+*
+*   WSAPROTOCOL_INFOW
+*
+***/
+
+#[repr(C)]
+pub struct WSAPROTOCOL_CHAIN {
+    pub ChainLen: i32,
+    pub ChainEntries: [DWORD; MAX_PROTOCOL_CHAIN],
+}
+
+#[repr(C)]
+pub struct WSAPROTOCOL_INFOW {
+    pub dwServiceFlags1: DWORD,
+    pub dwServiceFlags2: DWORD,
+    pub dwServiceFlags3: DWORD,
+    pub dwServiceFlags4: DWORD,
+    pub dwProviderFlags: DWORD,
+    pub ProviderId: GUID,
+    pub dwCatalogEntryId: DWORD,
+    pub ProtocolChain: WSAPROTOCOL_CHAIN,
+    pub iVersion: i32,
+    pub iAddressFamily: i32,
+    pub iMaxSockAddr: i32,
+    pub iMinSockAddr: i32,
+    pub iSocketType: i32,
+    pub iProtocol: i32,
+    pub iProtocolMaxOffset: i32,
+    pub iNetworkByteOrder: i32,
+    pub iSecurityScheme: i32,
+    pub dwMessageSize: DWORD,
+    pub dwProviderReserved: DWORD,
+    pub szProtocol: [u16; WSAPROTOCOL_LEN + 1],
+}
+
+impl WSAPROTOCOL_INFOW {
+    //=======================================================================
+    pub fn new () -> WSAPROTOCOL_INFOW {
+        unsafe { mem::zeroed() }
+    }
+}
+
+
+/****************************************************************************
+*
+*   ip_mreq
+*
+***/
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct ip_mreq {
+    pub imr_multiaddr: [u8; 4],
+    pub imr_interface: [u8; 4],
+}
+
+
+/****************************************************************************
+*
+*   linger
+*
+***/
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct linger {
+    pub l_onoff: u16,
+    pub l_linger: u16,
+}
+
+
+/****************************************************************************
+*
+*   ipv6_mreq
+*
+***/
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct ipv6_mreq {
+    pub ipv6mr_multiaddr: [u8; 16],
+    pub ipv6mr_interface: u32,
+}
+
+
 /****************************************************************************
 *
 *   sockaddr_in
@@ -222,7 +417,7 @@ impl sockaddr_in6 {
         sockaddr_in6 {
             sin6_family: AF_INET6 as i16,
             sin6_port: endian::net_16(addr.port()),
-            sin6_flowinfo: 0, // TODO: proper value
+            sin6_flowinfo: addr.flowinfo(),
             sin6_addr: [
                 endian::net_16(segments[0]),
                 endian::net_16(segments[1]),
@@ -233,7 +428,30 @@ impl sockaddr_in6 {
                 endian::net_16(segments[6]),
                 endian::net_16(segments[7]),
             ],
-            sin6_scope_id: 0, // TODO: proper value
+            sin6_scope_id: addr.scope_id(),
+        }
+    }
+}
+
+
+/****************************************************************************
+*
+*   sockaddr_un
+*
+***/
+
+#[repr(C)]
+pub struct sockaddr_un {
+    pub sun_family: u16,
+    pub sun_path: [u8; UNIX_PATH_MAX],
+}
+
+impl sockaddr_un {
+    //=======================================================================
+    pub fn new () -> sockaddr_un {
+        sockaddr_un {
+            sun_family: AF_UNIX as u16,
+            sun_path: [0; UNIX_PATH_MAX],
         }
     }
 }
@@ -271,6 +489,22 @@ impl sockaddr_storage {
         }
     }
 
+    //=======================================================================
+    pub fn from_raw (sockaddr: PVOID, len: i32) -> sockaddr_storage {
+        let mut storage = sockaddr_storage::new();
+        let len = cmp::min(len as usize, mem::size_of_val(&storage));
+
+        unsafe {
+            ptr::copy_nonoverlapping(
+                sockaddr as *const u8,
+                &mut storage as *mut _ as *mut u8,
+                len
+            );
+        }
+
+        storage
+    }
+
     //=======================================================================
     pub fn get_addr (&self) -> Option<net::SocketAddr> {
         match self.ss_family as i32 {
@@ -291,18 +525,23 @@ impl sockaddr_storage {
                 let addr: &sockaddr_in6 = unsafe { mem::transmute(self) };
                 let segments = addr.sin6_addr;
                 let ip = net::Ipv6Addr::new(
-                    segments[0],
-                    segments[1],
-                    segments[2],
-                    segments[3],
-                    segments[4],
-                    segments[5],
-                    segments[6],
-                    segments[7]
+                    endian::net_16(segments[0]),
+                    endian::net_16(segments[1]),
+                    endian::net_16(segments[2]),
+                    endian::net_16(segments[3]),
+                    endian::net_16(segments[4]),
+                    endian::net_16(segments[5]),
+                    endian::net_16(segments[6]),
+                    endian::net_16(segments[7])
                 );
                 let port = endian::net_16(addr.sin6_port);
 
-                Some(net::SocketAddr::new(net::IpAddr::V6(ip), port))
+                Some(net::SocketAddr::V6(net::SocketAddrV6::new(
+                    ip,
+                    port,
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id
+                )))
             },
             _ => None,
         }
@@ -310,6 +549,100 @@ impl sockaddr_storage {
 }
 
 
+/****************************************************************************
+*
+*   ADDRINFOEXW
+*
+***/
+
+#[repr(C)]
+pub struct ADDRINFOEXW {
+    pub ai_flags: i32,
+    pub ai_family: i32,
+    pub ai_socktype: i32,
+    pub ai_protocol: i32,
+    pub ai_addrlen: usize,
+    pub ai_canonname: LPWSTR,
+    pub ai_addr: PVOID,
+    pub ai_blob: PVOID,
+    pub ai_bufferlen: usize,
+    pub ai_provider: *mut GUID,
+    pub ai_next: *mut ADDRINFOEXW,
+}
+
+impl ADDRINFOEXW {
+    //=======================================================================
+    pub fn new () -> ADDRINFOEXW {
+        ADDRINFOEXW {
+            ai_flags: 0,
+            ai_family: 0,
+            ai_socktype: 0,
+            ai_protocol: 0,
+            ai_addrlen: 0,
+            ai_canonname: ptr::null_mut(),
+            ai_addr: ptr::null_mut(),
+            ai_blob: ptr::null_mut(),
+            ai_bufferlen: 0,
+            ai_provider: ptr::null_mut(),
+            ai_next: ptr::null_mut(),
+        }
+    }
+}
+
+
+/****************************************************************************
+*
+*   IP_ADAPTER_ADDRESSES
+*
+***/
+
+#[repr(C)]
+pub struct SOCKET_ADDRESS {
+    pub lpSockaddr: PVOID,
+    pub iSockaddrLength: i32,
+}
+
+#[repr(C)]
+pub struct IP_ADAPTER_UNICAST_ADDRESS {
+    pub Length: u32,
+    pub Flags: DWORD,
+    pub Next: *mut IP_ADAPTER_UNICAST_ADDRESS,
+    pub Address: SOCKET_ADDRESS,
+}
+
+#[repr(C)]
+pub struct IP_ADAPTER_DNS_SERVER_ADDRESS {
+    pub Length: u32,
+    pub Reserved: DWORD,
+    pub Next: *mut IP_ADAPTER_DNS_SERVER_ADDRESS,
+    pub Address: SOCKET_ADDRESS,
+}
+
+// Only the leading fields that are read; entries are always allocated by
+// GetAdaptersAddresses and never by value. The unicast entry shares its
+// leading fields with the DNS server entry.
+#[repr(C)]
+pub struct IP_ADAPTER_ADDRESSES {
+    pub Length: u32,
+    pub IfIndex: u32,
+    pub Next: *mut IP_ADAPTER_ADDRESSES,
+    pub AdapterName: *mut i8,
+    pub FirstUnicastAddress: *mut IP_ADAPTER_UNICAST_ADDRESS,
+    pub FirstAnycastAddress: PVOID,
+    pub FirstMulticastAddress: PVOID,
+    pub FirstDnsServerAddress: *mut IP_ADAPTER_DNS_SERVER_ADDRESS,
+    pub DnsSuffix: LPWSTR,
+    pub Description: LPWSTR,
+    pub FriendlyName: LPWSTR,
+    pub PhysicalAddress: [u8; 8],
+    pub PhysicalAddressLength: u32,
+    pub Flags: u32,
+    pub Mtu: u32,
+    pub IfType: u32,
+    pub OperStatus: i32,
+}
+
+
 /****************************************************************************
 *
 *   OVERLAPPED
@@ -340,6 +673,42 @@ impl OVERLAPPED {
 }
 
 
+/****************************************************************************
+*
+*   IO_STATUS_BLOCK
+*
+***/
+
+#[repr(C)]
+pub struct IO_STATUS_BLOCK {
+    pub Status: PVOID, // Union of NTSTATUS and PVOID
+    pub Information: usize,
+}
+
+impl IO_STATUS_BLOCK {
+    //=======================================================================
+    pub fn new () -> IO_STATUS_BLOCK {
+        IO_STATUS_BLOCK {
+            Status: ptr::null_mut(),
+            Information: 0,
+        }
+    }
+}
+
+
+/****************************************************************************
+*
+*   FILE_COMPLETION_INFORMATION
+*
+***/
+
+#[repr(C)]
+pub struct FILE_COMPLETION_INFORMATION {
+    pub Port: HANDLE,
+    pub Key: PVOID,
+}
+
+
 /****************************************************************************
 *
 *   WSABUF
@@ -425,22 +794,29 @@ impl WsaExtFn {
 
 #[link(name = "kernel32")]
 extern "stdcall" {
+    pub fn CancelIoEx (
+        hFile: HANDLE,              // IN
+        lpOverlapped: LPOVERLAPPED  // IN OPT
+    ) -> BOOL;
+
     pub fn CloseHandle (
         hObject: HANDLE // IN
     ) -> BOOL;
 
     pub fn GetLastError () -> u32;
 
-    pub fn FormatMessageA (
+    pub fn FormatMessageW (
         dwFlags: DWORD,             // IN
         lpSource: LPCVOID,          // IN OPT
         dwMessageId: DWORD,         // IN
         dwLanguageId: DWORD,        // IN
-        lpBuffer: LPTSTR,           // OUT
+        lpBuffer: LPWSTR,           // OUT
         nSize: DWORD,               // IN
         Arguments: *const VA_LIST   // IN OPT
     ) -> DWORD;
 
+    pub fn LocalFree (hMem: HLOCAL) -> HLOCAL;
+
     pub fn CreateIoCompletionPort (
         FileHandle: HANDLE,             // IN
         ExistingCompletionPort: HANDLE, // IN OPT
@@ -464,8 +840,44 @@ extern "stdcall" {
     ) -> BOOL;
 }
 
+#[link(name = "ntdll")]
+extern "stdcall" {
+    pub fn NtSetInformationFile (
+        FileHandle: HANDLE,                     // IN
+        IoStatusBlock: *mut IO_STATUS_BLOCK,    // OUT
+        FileInformation: PVOID,                 // IN
+        Length: u32,                            // IN
+        FileInformationClass: u32               // IN
+    ) -> i32;
+
+    pub fn RtlNtStatusToDosError (
+        Status: i32 // IN
+    ) -> u32;
+}
+
+#[link(name = "iphlpapi")]
+extern "stdcall" {
+    pub fn GetAdaptersAddresses (
+        Family: u32,                                    // IN
+        Flags: u32,                                     // IN
+        Reserved: PVOID,                                // IN
+        AdapterAddresses: *mut IP_ADAPTER_ADDRESSES,    // OUT OPT
+        SizePointer: *mut u32                           // IN OUT
+    ) -> u32;
+
+    pub fn if_nametoindex (
+        InterfaceName: *const i8    // IN
+    ) -> u32;
+}
+
 #[link(name = "Ws2_32")]
 extern "stdcall" {
+    pub fn accept (
+        s: SOCKET,          // IN
+        addr: PVOID,        // OUT OPT
+        addrlen: *mut i32   // IN OUT OPT
+    ) -> SOCKET;
+
     pub fn bind (
         s: SOCKET,      // IN
         name: PVOID,    // IN
@@ -476,6 +888,35 @@ extern "stdcall" {
         s: SOCKET   // IN
     ) -> i32;
 
+    pub fn connect (
+        s: SOCKET,      // IN
+        name: PVOID,    // IN
+        namelen: i32    // IN
+    ) -> i32;
+
+    pub fn FreeAddrInfoExW (
+        pAddrInfoEx: *mut ADDRINFOEXW   // IN
+    );
+
+    pub fn GetAddrInfoExW (
+        pName: *const u16,                                                  // IN
+        pServiceName: *const u16,                                           // IN OPT
+        dwNameSpace: DWORD,                                                 // IN
+        lpNspId: *mut GUID,                                                 // IN OPT
+        hints: *const ADDRINFOEXW,                                          // IN OPT
+        ppResult: *mut *mut ADDRINFOEXW,                                    // OUT
+        timeout: PVOID,                                                     // IN OPT
+        lpOverlapped: LPOVERLAPPED,                                         // IN OPT
+        lpCompletionRoutine: Option<LOOKUPSERVICE_COMPLETION_ROUTINE>,      // IN OPT
+        lpNameHandle: *mut HANDLE                                           // OUT OPT
+    ) -> i32;
+
+    pub fn getpeername (
+        s: SOCKET,          // IN
+        name: PVOID,        // OUT
+        namelen: *mut i32   // IN OUT
+    ) -> i32;
+
     pub fn getsockname (
         s: SOCKET,          // IN
         name: PVOID,        // OUT
@@ -487,6 +928,14 @@ extern "stdcall" {
         backlog: i32    // IN
     ) -> i32;
 
+    pub fn setsockopt (
+        s: SOCKET,          // IN
+        level: i32,         // IN
+        optname: i32,       // IN
+        optval: LPCVOID,    // IN
+        optlen: i32         // IN
+    ) -> i32;
+
     pub fn socket (
         af: i32,        // IN
         socktype: i32,  // IN
@@ -495,8 +944,22 @@ extern "stdcall" {
 
     pub fn WSACleanup () -> i32;
 
+    pub fn WSADuplicateSocketW (
+        s: SOCKET,                                  // IN
+        dwProcessId: DWORD,                         // IN
+        lpProtocolInfo: *mut WSAPROTOCOL_INFOW      // OUT
+    ) -> i32;
+
     pub fn WSAGetLastError () -> i32;
 
+    pub fn WSAGetOverlappedResult (
+        s: SOCKET,                  // IN
+        lpOverlapped: LPOVERLAPPED, // IN
+        lpcbTransfer: LPDWORD,      // OUT
+        fWait: BOOL,                // IN
+        lpdwFlags: LPDWORD          // OUT
+    ) -> BOOL;
+
     pub fn WSAIoctl (
         s: SOCKET,                                      // IN
         dwIoControlCode: DWORD,                         // IN
@@ -519,6 +982,27 @@ extern "stdcall" {
         lpCompletionRoutine: Option<WSA_COMPL_ROUTINE>  // IN
     ) -> i32;
 
+    pub fn WSARecvFrom (
+        s: SOCKET,                                      // IN
+        lpBuffers: LPWSABUF,                            // IN OUT
+        dwBufferCount: DWORD,                           // IN
+        lpNumberOfBytesRecvd: LPDWORD,                  // OUT
+        lpFlags: LPDWORD,                               // IN OUT
+        lpFrom: PVOID,                                  // OUT
+        lpFromlen: LPINT,                               // IN OUT
+        lpOverlapped: LPOVERLAPPED,                     // IN
+        lpCompletionRoutine: Option<WSA_COMPL_ROUTINE>  // IN
+    ) -> i32;
+
+    pub fn WSASocketW (
+        af: i32,                                    // IN
+        socktype: i32,                              // IN
+        protocol: i32,                              // IN
+        lpProtocolInfo: *const WSAPROTOCOL_INFOW,   // IN OPT
+        g: u32,                                     // IN
+        dwFlags: DWORD                              // IN
+    ) -> SOCKET;
+
     pub fn WSAStartup (
         wVersionRequested: u16, // IN
         lpWSAData: *mut WSAData // OUT
@@ -533,6 +1017,18 @@ extern "stdcall" {
         lpOverlapped: LPOVERLAPPED,                     // IN
         lpCompletionRoutine: Option<WSA_COMPL_ROUTINE>  // IN
     ) -> i32;
+
+    pub fn WSASendTo (
+        s: SOCKET,                                      // IN
+        lpBuffers: LPWSABUF,                            // IN
+        dwBufferCount: DWORD,                           // IN
+        lpNumberOfBytesSent: LPDWORD,                   // OUT
+        dwFlags: DWORD,                                 // IN
+        lpTo: PVOID,                                    // IN
+        iTolen: i32,                                    // IN
+        lpOverlapped: LPOVERLAPPED,                     // IN
+        lpCompletionRoutine: Option<WSA_COMPL_ROUTINE>  // IN
+    ) -> i32;
 }
 
 